@@ -0,0 +1,6 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use smo_rusty_online::fuzz;
+
+// Everything a client sends on one connection, split into packets by the server
+fuzz_target!(|data: &[u8]| fuzz::stream(data));
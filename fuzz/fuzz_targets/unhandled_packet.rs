@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use smo_rusty_online::{fuzz, packet::packets::UnhandledPacket::UnhandledPacket};
+
+fuzz_target!(|data: &[u8]| fuzz::packet::<UnhandledPacket>(data));
@@ -0,0 +1,360 @@
+use std::{
+    net::IpAddr,
+    sync::Arc
+};
+use axum::{
+    Json,
+    Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put}
+};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    sync::RwLock
+};
+use uuid::Uuid;
+use tracing::{error, info, warn};
+use crate::{
+    client::Client,
+    registry::PlayerEntry,
+    server::{Server, ServerWrapper},
+    settings::{Settings, BannedPlayers},
+    shines::ShineSync,
+    stages::{self, Kingdom, KEEP_SCENARIO}
+};
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+// Secrets are shown as this, patching it back leaves them as they were
+const REDACTED: &str = "[redacted]";
+const SECRETS: [(&str, &str); 3] = [("admin", "token"), ("discord", "token"), ("allowed_players", "password")];
+
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn bad_request(message: String) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message)
+}
+
+fn not_found(selector: &str) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, format!("No player matches {:?}", selector))
+}
+
+#[derive(Serialize)]
+pub struct CostumeInfo {
+    pub body: String,
+    pub cap: String,
+}
+
+#[derive(Serialize)]
+pub struct TimeInfo {
+    pub minutes: u16,
+    pub seconds: u8,
+}
+
+#[derive(Serialize)]
+pub struct PlayerInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub slot: usize,
+    pub connected: bool,
+    pub ip: Option<IpAddr>,
+    pub stage: Option<String>,
+    pub kingdom: Option<Kingdom>,
+    // None until the first GamePacket
+    pub scenario: Option<u8>,
+    pub relayed_scenario: String,
+    pub is_2d: bool,
+    pub costume: Option<CostumeInfo>,
+    pub seeking: bool,
+    pub time: TimeInfo,
+    pub shines: usize,
+}
+
+impl PlayerInfo {
+    fn of(player: &PlayerEntry, client: &Client) -> PlayerInfo {
+        PlayerInfo {
+            id: client.id,
+            name: client.name.to_string(),
+            slot: player.slot,
+            connected: player.is_connected(),
+            ip: client.address.map(|address| address.ip()),
+            stage: client.metadata.last_game_packet.as_ref().map(|game_packet| game_packet.packet.stage.to_string()),
+            kingdom: client.metadata.kingdom,
+            scenario: if client.metadata.scenario == 200 { None } else { Some(client.metadata.scenario) },
+            relayed_scenario: client.metadata.relayed_scenario.to_string(),
+            is_2d: client.metadata.is_2d,
+            costume: client.current_costume.as_ref().map(|costume| CostumeInfo {
+                body: costume.packet.body_name.to_string(),
+                cap: costume.packet.cap_name.to_string(),
+            }),
+            seeking: client.metadata.seeking,
+            time: TimeInfo {
+                minutes: client.metadata.time.minutes,
+                seconds: client.metadata.time.seconds,
+            },
+            shines: client.metadata.shine_sync.len(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WarpRequest {
+    pub stage: String,
+    pub scenario: Option<i8>,
+    pub id: Option<String>,
+}
+
+/// HTTP/JSON API for running the server headless, every route needs `Authorization: Bearer <token>`
+pub struct AdminApi;
+
+impl AdminApi {
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let (enabled, address, token) = {
+            let admin = &server.read().await.settings.admin;
+            (admin.enabled, admin.address.to_string(), admin.token.clone())
+        };
+        if !enabled {
+            return;
+        }
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => {
+                warn!("Admin API is enabled but has no token, not starting it");
+                return;
+            }
+        };
+
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(%address, %error, "Admin API failed to bind");
+                return;
+            }
+        };
+        info!(%address, "Admin API listening");
+
+        if let Err(error) = axum::serve(listener, AdminApi::router(server, token)).await {
+            error!(%error, "Admin API stopped");
+        }
+    }
+
+    pub fn router(server: Arc<RwLock<Server>>, token: String) -> Router {
+        Router::new()
+            .route("/players", get(AdminApi::players))
+            .route("/players/{selector}", get(AdminApi::player))
+            .route("/players/{selector}/warp", post(AdminApi::warp))
+            .route("/players/{selector}/kick", post(AdminApi::kick))
+            .route("/players/{selector}/shines/sync", post(AdminApi::sync_player_shines))
+            .route("/shines", get(AdminApi::shines))
+            .route("/shines/sync", post(AdminApi::sync_shines))
+            .route("/settings", get(AdminApi::settings).patch(AdminApi::update_settings))
+            .route("/bans", get(AdminApi::bans))
+            .route("/bans/players/{id}", put(AdminApi::ban_player).delete(AdminApi::unban_player))
+            .route("/bans/ips/{ip}", put(AdminApi::ban_ip).delete(AdminApi::unban_ip))
+            .layer(middleware::from_fn_with_state(Arc::new(token), AdminApi::authorize))
+            .with_state(server)
+    }
+
+    async fn authorize(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+        let authorized = request.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+
+        if !authorized {
+            return ApiError(StatusCode::UNAUTHORIZED, "Missing or wrong token".to_string()).into_response();
+        }
+        next.run(request).await
+    }
+
+    /// Everyone who connected since the server started, in slot order
+    async fn players(State(server): State<Arc<RwLock<Server>>>) -> ApiResult<Vec<PlayerInfo>> {
+        let players = server.read().await.players.clone();
+        let mut infos: Vec<PlayerInfo> = Vec::new();
+        for player in players.snapshot() {
+            infos.push(PlayerInfo::of(&player, &*player.client.read().await));
+        }
+        Ok(Json(infos))
+    }
+
+    async fn player(State(server): State<Arc<RwLock<Server>>>, Path(selector): Path<String>) -> ApiResult<PlayerInfo> {
+        let players = server.read().await.players.clone();
+        let player = players.snapshot()
+            .into_iter()
+            .find(|player| player.name.eq_ignore_ascii_case(&selector) || player.id.to_string() == selector)
+            .ok_or_else(|| not_found(&selector))?;
+        let info = PlayerInfo::of(&player, &*player.client.read().await);
+        Ok(Json(info))
+    }
+
+    async fn connected_clients(server: Arc<RwLock<Server>>, selector: &str) -> Result<Vec<Arc<RwLock<Client>>>, ApiError> {
+        let clients = ServerWrapper::find_clients(server, selector).await;
+        if clients.is_empty() {
+            return Err(not_found(selector));
+        }
+        Ok(clients)
+    }
+
+    async fn warp(State(server): State<Arc<RwLock<Server>>>, Path(selector): Path<String>, Json(request): Json<WarpRequest>) -> ApiResult<Value> {
+        let (stage, scenario) = stages::resolve_warp_target(&request.stage, request.scenario.unwrap_or(KEEP_SCENARIO))
+            .map_err(bad_request)?;
+        let id = request.id.unwrap_or_default();
+
+        let mut warped: Vec<String> = Vec::new();
+        for c in AdminApi::connected_clients(server.clone(), &selector).await? {
+            if ServerWrapper::warp(server.clone(), c.clone(), &stage, &id, scenario).await {
+                warped.push(c.read().await.name.to_string());
+            }
+        }
+        Ok(Json(json!({ "warped": warped, "stage": stage, "scenario": scenario })))
+    }
+
+    async fn kick(State(server): State<Arc<RwLock<Server>>>, Path(selector): Path<String>) -> ApiResult<Value> {
+        let mut kicked: Vec<String> = Vec::new();
+        for c in AdminApi::connected_clients(server.clone(), &selector).await? {
+            ServerWrapper::kick(server.clone(), c.clone()).await;
+            kicked.push(c.read().await.name.to_string());
+        }
+        Ok(Json(json!({ "kicked": kicked })))
+    }
+
+    async fn shines(State(server): State<Arc<RwLock<Server>>>) -> ApiResult<Vec<u32>> {
+        Ok(Json(server.read().await.shines.all()))
+    }
+
+    async fn sync_shines(State(server): State<Arc<RwLock<Server>>>) -> ApiResult<Value> {
+        let sent = ShineSync::sync_all(server).await;
+        Ok(Json(json!({ "sent": sent })))
+    }
+
+    async fn sync_player_shines(State(server): State<Arc<RwLock<Server>>>, Path(selector): Path<String>) -> ApiResult<Value> {
+        let mut sent = 0;
+        for c in AdminApi::connected_clients(server.clone(), &selector).await? {
+            sent += ShineSync::sync(server.clone(), c).await;
+        }
+        Ok(Json(json!({ "sent": sent })))
+    }
+
+    async fn settings(State(server): State<Arc<RwLock<Server>>>) -> ApiResult<Value> {
+        let settings = serde_json::to_value(&server.read().await.settings)
+            .map_err(|error| ApiError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        Ok(Json(redacted(settings)))
+    }
+
+    /// Applies a JSON merge patch (RFC 7396), tick rates and addresses only change on restart.
+    /// The token is only read on startup, so changing it here is refused. Players who can't join anymore are kicked
+    async fn update_settings(State(server): State<Arc<RwLock<Server>>>, Json(patch): Json<Value>) -> ApiResult<Value> {
+        if patch.get("admin").and_then(|admin| admin.get("token")).is_some() {
+            return Err(bad_request("admin.token can only be changed in the settings file and applies on restart".to_string()));
+        }
+
+        let mut s = server.write().await;
+        let current = serde_json::to_value(&s.settings)
+            .map_err(|error| ApiError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        let mut settings = current.clone();
+        merge_patch(&mut settings, &patch);
+        for (table, key) in SECRETS {
+            if settings[table][key] == REDACTED {
+                settings[table][key] = current[table][key].clone();
+            }
+        }
+
+        let updated = serde_json::from_value::<Settings>(settings.clone())
+            .map_err(|error| bad_request(format!("Invalid settings: {}", error)))?;
+        updated.validate().map_err(|error| bad_request(format!("Invalid settings: {}", error)))?;
+        s.settings = updated;
+        drop(s);
+
+        let kicked = ServerWrapper::enforce_access(server).await;
+        if !kicked.is_empty() {
+            info!(players = %kicked.join(", "), "Kicked players who can't join anymore");
+        }
+        Ok(Json(redacted(settings)))
+    }
+
+    async fn bans(State(server): State<Arc<RwLock<Server>>>) -> ApiResult<BannedPlayers> {
+        Ok(Json(server.read().await.settings.banned_players.clone()))
+    }
+
+    /// Also kicks the player if they are connected and bans are enabled
+    async fn ban_player(State(server): State<Arc<RwLock<Server>>>, Path(id): Path<Uuid>) -> ApiResult<BannedPlayers> {
+        {
+            let banned_players = &mut server.write().await.settings.banned_players;
+            if !banned_players.players.contains(&id) {
+                banned_players.players.push(id);
+            }
+        }
+        ServerWrapper::enforce_access(server.clone()).await;
+        AdminApi::bans(State(server)).await
+    }
+
+    async fn unban_player(State(server): State<Arc<RwLock<Server>>>, Path(id): Path<Uuid>) -> ApiResult<BannedPlayers> {
+        server.write().await.settings.banned_players.players.retain(|banned| *banned != id);
+        AdminApi::bans(State(server)).await
+    }
+
+    /// Also kicks everyone connected from the address if bans are enabled
+    async fn ban_ip(State(server): State<Arc<RwLock<Server>>>, Path(ip): Path<IpAddr>) -> ApiResult<BannedPlayers> {
+        {
+            let banned_players = &mut server.write().await.settings.banned_players;
+            if !banned_players.bans_address(&ip) {
+                banned_players.ip_addresses.push(ip.to_string());
+            }
+        }
+        ServerWrapper::enforce_access(server.clone()).await;
+        AdminApi::bans(State(server)).await
+    }
+
+    async fn unban_ip(State(server): State<Arc<RwLock<Server>>>, Path(ip): Path<IpAddr>) -> ApiResult<BannedPlayers> {
+        server.write().await.settings.banned_players.ip_addresses
+            .retain(|banned| !banned.parse::<IpAddr>().is_ok_and(|banned| banned == ip));
+        AdminApi::bans(State(server)).await
+    }
+}
+
+/// RFC 7396, objects are merged recursively and `null` removes a key
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn redacted(mut settings: Value) -> Value {
+    for (table, key) in SECRETS {
+        if let Some(secret) = settings.get_mut(table).and_then(|table| table.get_mut(key)) {
+            if !secret.is_null() {
+                *secret = REDACTED.into();
+            }
+        }
+    }
+    settings
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
@@ -0,0 +1,413 @@
+//! Decodes hex dumps and packet captures into readable packets
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io::{self, Read},
+    process
+};
+use uuid::Uuid;
+use smo_rusty_online::{
+    capture::{self, Direction, MAGIC},
+    packet::{
+        PacketHeader::{PacketHeader, SIZE as PACKET_HEADER_SIZE},
+        PacketType::PacketType,
+        framing::MAX_FRAME_SIZE,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            CapPacket::CapPacket,
+            CapturePacket::CapturePacket,
+            ChangeStagePacket::ChangeStagePacket,
+            ConnectPacket::{ConnectPacket, ConnectionTypes},
+            CostumePacket::CostumePacket,
+            GamePacket::GamePacket,
+            InitPacket::InitPacket,
+            PlayerPacket::PlayerPacket,
+            ShinePacket::ShinePacket,
+            TagPacket::{TagPacket, TagUpdate}
+        }
+    },
+    stages
+};
+
+const USAGE: &str = "\
+Usage: smo-inspect [options] [file...]
+
+Reads packet captures (see `capture.enabled`) or hex dumps from the files, or stdin when none are given.
+Hex dumps are split into frames by blank lines, whitespace, `0x` prefixes, commas and `#` comments are ignored.
+
+Options:
+  -p, --player <name or uuid>  Only packets about this player, uuids can be shortened. Can be repeated
+  -t, --type <type>            Only this packet type, e.g. player or Costume. Can be repeated
+  -m, --malformed              Only packets with problems
+  -h, --help                   Show this message";
+
+// Indexed by `PacketType as u16`
+const TYPE_NAMES: [&str; 13] = [
+    "Unknown", "Init", "Player", "Cap", "Game", "Tag", "Connect",
+    "Disconnect", "Costume", "Shine", "Capture", "ChangeStage", "Command",
+];
+
+struct Options {
+    files: Vec<String>,
+    players: Vec<String>,
+    types: Vec<u16>,
+    malformed: bool,
+}
+
+/// A frame as it was read from or written to a socket, it can hold several packets
+struct Frame {
+    // Where the frame came from, e.g. "+1.250000s in #0" or "line 4"
+    origin: String,
+    bytes: Vec<u8>,
+}
+
+struct Packet {
+    id: Uuid,
+    packet_type: u16,
+    size: i16,
+    // Sent in ConnectPacket, later packets from the same uuid are labelled with it
+    name: Option<String>,
+    details: String,
+    problems: Vec<String>,
+}
+
+#[derive(Default)]
+struct Totals {
+    frames: usize,
+    packets: usize,
+    shown: usize,
+    malformed: usize,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let inputs: Vec<(String, io::Result<Vec<u8>>)> = if options.files.is_empty() {
+        let mut data = Vec::new();
+        let result = io::stdin().read_to_end(&mut data).map(|_| data);
+        vec![("stdin".to_string(), result)]
+    } else {
+        options.files.iter().map(|file| (file.to_string(), fs::read(file))).collect()
+    };
+
+    let mut names: HashMap<Uuid, String> = HashMap::new();
+    let mut totals = Totals::default();
+    let mut failed = false;
+    for (source, data) in inputs {
+        let frames = data.and_then(|data| {
+            if data.starts_with(MAGIC) {
+                capture_frames(&data)
+            } else {
+                hex_frames(&data)
+            }
+        });
+        match frames {
+            Ok(frames) => {
+                for frame in frames {
+                    inspect(&frame, &options, &mut names, &mut totals);
+                }
+            },
+            Err(error) => {
+                eprintln!("{}: {}", source, error);
+                failed = true;
+            },
+        }
+    }
+
+    println!(
+        "{} frames, {} packets, {} shown, {} malformed",
+        totals.frames, totals.packets, totals.shown, totals.malformed
+    );
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        files: vec![],
+        players: vec![],
+        types: vec![],
+        malformed: false,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "-p" | "--player" => {
+                let player = args.next().ok_or("--player needs a name or uuid")?;
+                options.players.push(player.to_lowercase());
+            },
+            "-t" | "--type" => {
+                let name = args.next().ok_or("--type needs a packet type")?;
+                let name = name.to_lowercase();
+                let name = name.trim_end_matches("packet");
+                match TYPE_NAMES.iter().position(|type_name| type_name.to_lowercase() == name) {
+                    Some(packet_type) => options.types.push(packet_type as u16),
+                    None => return Err(format!("Unknown packet type {:?}, expected one of {}", name, TYPE_NAMES.join(", "))),
+                }
+            },
+            "-m" | "--malformed" => options.malformed = true,
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option {}", arg)),
+            _ => options.files.push(arg),
+        }
+    }
+    // `-` reads stdin, same as no files
+    options.files.retain(|file| file != "-");
+    Ok(options)
+}
+
+fn capture_frames(data: &[u8]) -> io::Result<Vec<Frame>> {
+    let records = capture::read_records(&mut &data[..])?;
+    Ok(records.into_iter().map(|record| {
+        let direction = match record.direction {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+            Direction::Closed => "closed",
+        };
+        Frame {
+            origin: format!("+{:.6}s {} #{}", record.micros as f64 / 1e6, direction, record.connection),
+            bytes: record.frame,
+        }
+    }).collect())
+}
+
+fn hex_frames(data: &[u8]) -> io::Result<Vec<Frame>> {
+    let text = String::from_utf8_lossy(data);
+    let mut frames = vec![];
+    let mut current = Frame { origin: String::new(), bytes: vec![] };
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let digits: String = line
+            .replace("0x", "")
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ',')
+            .collect();
+
+        if digits.is_empty() {
+            if !current.bytes.is_empty() {
+                frames.push(current);
+                current = Frame { origin: String::new(), bytes: vec![] };
+            }
+            continue;
+        }
+        if !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {} is not hex", number + 1)));
+        }
+
+        if current.bytes.is_empty() {
+            current.origin = format!("line {}", number + 1);
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).unwrap();
+            current.bytes.push(u8::from_str_radix(pair, 16).unwrap());
+        }
+    }
+    if !current.bytes.is_empty() {
+        frames.push(current);
+    }
+    Ok(frames)
+}
+
+fn inspect(frame: &Frame, options: &Options, names: &mut HashMap<Uuid, String>, totals: &mut Totals) {
+    totals.frames += 1;
+    // Closed capture records have no frame
+    if frame.bytes.is_empty() {
+        if options.players.is_empty() && options.types.is_empty() && !options.malformed {
+            println!("[{}]", frame.origin);
+        }
+        return;
+    }
+
+    for packet in split_frame(&frame.bytes) {
+        totals.packets += 1;
+        if !packet.problems.is_empty() {
+            totals.malformed += 1;
+        }
+        if let Some(name) = packet.name.as_ref() {
+            names.insert(packet.id, name.to_string());
+        }
+
+        let name = names.get(&packet.id);
+        let player_matches = options.players.is_empty() || options.players.iter().any(|player| {
+            packet.id.to_string().starts_with(player.as_str())
+                || name.is_some_and(|name| name.to_lowercase() == *player)
+        });
+        let type_matches = options.types.is_empty() || options.types.contains(&packet.packet_type);
+        if !player_matches || !type_matches || (options.malformed && packet.problems.is_empty()) {
+            continue;
+        }
+        totals.shown += 1;
+
+        let type_name = TYPE_NAMES.get(packet.packet_type as usize).copied().unwrap_or("?");
+        let who = match name {
+            Some(name) => format!("{} ({})", packet.id, name),
+            None => packet.id.to_string(),
+        };
+        println!("[{}] {} {} {}B {}", frame.origin, who, type_name, packet.size, packet.details);
+        for problem in packet.problems.iter() {
+            println!("    ! {}", problem);
+        }
+    }
+}
+
+/// Walks the packets in a frame, everything odd about them is kept as a problem rather than failing
+fn split_frame(bytes: &[u8]) -> Vec<Packet> {
+    let mut packets = vec![];
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        if rest.len() < PACKET_HEADER_SIZE {
+            packets.push(Packet {
+                id: Uuid::nil(),
+                packet_type: PacketType::Unknown as u16,
+                size: rest.len() as i16,
+                name: None,
+                details: String::new(),
+                problems: vec![format!("{} trailing bytes, a header is {}", rest.len(), PACKET_HEADER_SIZE)],
+            });
+            break;
+        }
+
+        let mut header = IPacket::<PacketHeader>::new();
+        header.deserialize(&rest[..PACKET_HEADER_SIZE]);
+        let raw_type = u16::from_le_bytes([rest[16], rest[17]]);
+        let size = header.packet.packet_size;
+
+        let mut problems = vec![];
+        if raw_type as usize >= TYPE_NAMES.len() {
+            problems.push(format!("unknown packet type {}", raw_type));
+        }
+        if size < 0 {
+            problems.push(format!("negative size {}", size));
+            packets.push(Packet { id: header.packet.id, packet_type: raw_type, size, name: None, details: String::new(), problems });
+            break;
+        }
+        // The server drops everything after a frame this large, so there's nothing more to split
+        if PACKET_HEADER_SIZE + size as usize > MAX_FRAME_SIZE {
+            problems.push(format!("size {} makes a frame larger than {} bytes", size, MAX_FRAME_SIZE));
+            packets.push(Packet { id: header.packet.id, packet_type: raw_type, size, name: None, details: String::new(), problems });
+            break;
+        }
+
+        let body = &rest[PACKET_HEADER_SIZE..];
+        let body = if body.len() < size as usize {
+            problems.push(format!("truncated, the header says {} bytes but {} follow", size, body.len()));
+            body
+        } else {
+            &body[..size as usize]
+        };
+
+        let (details, expected) = describe(header.packet.packet_type, body);
+        if let Some(expected) = expected {
+            if size as usize != expected {
+                problems.push(format!("size {} but a {} packet is {}", size, TYPE_NAMES[raw_type as usize], expected));
+            }
+        }
+
+        let name = (header.packet.packet_type == PacketType::Connect).then(|| decode::<ConnectPacket>(body).packet.client_name);
+
+        packets.push(Packet { id: header.packet.id, packet_type: raw_type, size, name, details, problems });
+        offset += PACKET_HEADER_SIZE + body.len();
+    }
+    packets
+}
+
+/// Decodes a body padded with zeroes so short packets still decode
+fn decode<T>(body: &[u8]) -> IPacket<T>
+where IPacket<T>: IPacketTrait
+{
+    let mut buffer = [0; MAX_FRAME_SIZE];
+    let length = body.len().min(MAX_FRAME_SIZE);
+    buffer[..length].copy_from_slice(&body[..length]);
+    let mut packet = IPacket::<T>::new();
+    packet.deserialize(&buffer);
+    packet
+}
+
+/// Readable fields and the size the packet should have, unknown packets have no fixed size
+fn describe(packet_type: PacketType, body: &[u8]) -> (String, Option<usize>) {
+    match packet_type {
+        PacketType::Init => {
+            let p = decode::<InitPacket>(body);
+            (format!("max players {}", p.packet.max_players), Some(p.packet_size))
+        },
+        PacketType::Player => {
+            let p = decode::<PlayerPacket>(body);
+            let position = p.packet.position;
+            (
+                format!("position ({:.1}, {:.1}, {:.1}) act {} sub act {}", position.x, position.y, position.z, p.packet.act, p.packet.sub_act),
+                Some(p.packet_size)
+            )
+        },
+        PacketType::Cap => {
+            let p = decode::<CapPacket>(body);
+            let position = p.packet.position;
+            (
+                format!("position ({:.1}, {:.1}, {:.1}) {} {:?}", position.x, position.y, position.z, if p.packet.cap_out { "out" } else { "on head" }, p.packet.cap_animation),
+                Some(p.packet_size)
+            )
+        },
+        PacketType::Game => {
+            let p = decode::<GamePacket>(body);
+            (
+                format!("{} scenario {}{}", stages::friendly_name(&p.packet.stage), p.packet.scenario_num, if p.packet.is_2d { " 2D" } else { "" }),
+                Some(p.packet_size)
+            )
+        },
+        PacketType::Tag => {
+            let p = decode::<TagPacket>(body);
+            let update = match p.packet.update_type {
+                TagUpdate::Time => "time",
+                TagUpdate::State => "state",
+                TagUpdate::Both => "time and state",
+            };
+            (
+                format!("{} {} {}:{:02}", update, if p.packet.is_it { "seeker" } else { "hider" }, p.packet.minutes, p.packet.seconds),
+                Some(p.packet_size)
+            )
+        },
+        PacketType::Connect => {
+            let p = decode::<ConnectPacket>(body);
+            let connection = match p.packet.connection_type {
+                ConnectionTypes::FirstConnection => "first connection",
+                ConnectionTypes::Reconnecting => "reconnecting",
+            };
+            (format!("{:?} {} max players {}", p.packet.client_name, connection, p.packet.max_players), Some(p.packet_size))
+        },
+        PacketType::Disconnect => (String::new(), Some(0)),
+        PacketType::Costume => {
+            let p = decode::<CostumePacket>(body);
+            (format!("body {:?} cap {:?}", p.packet.body_name, p.packet.cap_name), Some(p.packet_size))
+        },
+        PacketType::Shine => {
+            let p = decode::<ShinePacket>(body);
+            (format!("shine {}", p.packet.shine_id), Some(p.packet_size))
+        },
+        PacketType::Capture => {
+            let p = decode::<CapturePacket>(body);
+            (format!("capture {:?}", p.packet.module_name), Some(p.packet_size))
+        },
+        PacketType::ChangeStage => {
+            let p = decode::<ChangeStagePacket>(body);
+            (
+                format!("to {} id {:?} scenario {} sub scenario {}", stages::friendly_name(&p.packet.stage), p.packet.id, p.packet.scenario, p.packet.sub_scenario_type),
+                Some(p.packet_size)
+            )
+        },
+        PacketType::Unknown | PacketType::Command => (format!("{} bytes", body.len()), None),
+    }
+}
@@ -0,0 +1,414 @@
+//! Simulates players against a server and reports how it keeps up
+use std::{
+    env,
+    fs,
+    net::SocketAddr,
+    process,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering}
+    },
+    time::Duration
+};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use smo_rusty_online::{
+    bot::{self, Bot, BotReceiver, BotSender},
+    builder::ServerBuilder,
+    packet::{
+        PacketHeader::SIZE as PACKET_HEADER_SIZE,
+        PacketType::PacketType,
+        packets::PlayerPacket::PlayerPacket
+    },
+    stages::{KINGDOMS, MAX_SCENARIO}
+};
+
+const USAGE: &str = "\
+Usage: smo-load [options]
+
+Connects simulated players that send like the game does: a PlayerPacket every frame, a CapPacket a few times
+a second, and now and then a stage change or a new costume. Prints throughput, relay latency and the server's
+CPU and memory every report interval, then totals for the whole run.
+
+Without --address a server is started in this process and measured along with the players.
+
+Options:
+  -n, --players <count>    Simulated players, default 8
+  -d, --duration <secs>    How long to run, default 30
+  -a, --address <addr>     Server to connect to, e.g. 127.0.0.1:1027
+  -p, --pid <pid>          Server process to sample CPU and memory from, needs /proc
+  -r, --report <secs>      Seconds between reports, default 5
+  -h, --help               Show this message";
+
+// The game sends its position once a frame
+const FRAME: Duration = Duration::from_micros(16_667);
+const CAP_EVERY_FRAMES: u64 = 6;
+// Mean frames between stage changes and costume changes, picked at random so players don't change in step
+const STAGE_CHANGE_FRAMES: u64 = 60 * 30;
+const COSTUME_CHANGE_FRAMES: u64 = 60 * 90;
+// Connecting everyone at once isn't what a server sees
+const CONNECT_INTERVAL: Duration = Duration::from_millis(50);
+// `/proc/<pid>/stat` counts in clock ticks, 100 a second on every Linux we run on
+const CLOCK_TICKS: f64 = 100.0;
+const COSTUMES: [&str; 4] = ["Mario", "MarioTuxedo", "MarioCaptain", "MarioPirate"];
+
+struct Options {
+    players: usize,
+    duration: Duration,
+    address: Option<String>,
+    pid: Option<u32>,
+    report: Duration,
+}
+
+/// Counted by every player, read and reset by the reporter
+#[derive(Default)]
+struct Stats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    bytes_received: AtomicU64,
+    players_sent: AtomicU64,
+    players_received: AtomicU64,
+    connected: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    // Micros between sending a PlayerPacket and another player receiving it
+    latencies: Mutex<Vec<u32>>,
+}
+
+/// Totals for the final summary
+#[derive(Default)]
+struct Totals {
+    sent: u64,
+    received: u64,
+    bytes_received: u64,
+    players_sent: u64,
+    players_received: u64,
+    latencies: Vec<u32>,
+}
+
+struct Usage {
+    cpu_ticks: u64,
+    rss_kb: u64,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        players: 8,
+        duration: Duration::from_secs(30),
+        address: None,
+        pid: None,
+        report: Duration::from_secs(5),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "-n" | "--players" => options.players = number(&value("--players")?)?,
+            "-d" | "--duration" => options.duration = Duration::from_secs(number(&value("--duration")?)?),
+            "-a" | "--address" => options.address = Some(value("--address")?),
+            "-p" | "--pid" => options.pid = Some(number(&value("--pid")?)?),
+            "-r" | "--report" => options.report = Duration::from_secs(number::<u64>(&value("--report")?)?.max(1)),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{:?} isn't a number", value))
+}
+
+/// xorshift, good enough to spread players out and saves a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// True once every `frames` calls on average
+    fn one_in(&mut self, frames: u64) -> bool {
+        self.next().is_multiple_of(frames)
+    }
+}
+
+/// Micros since the run started, wraps after 71 minutes which is fine for differences
+fn timestamp(started: Instant) -> u32 {
+    started.elapsed().as_micros() as u32
+}
+
+async fn run_player(index: usize, address: SocketAddr, stats: Arc<Stats>, started: Instant, stop: Arc<AtomicBool>) {
+    time::sleep(CONNECT_INTERVAL * index as u32).await;
+    let bot = match Bot::connect(address, &format!("load{}", index)).await {
+        Ok(bot) => bot,
+        Err(error) => {
+            eprintln!("load{} failed to connect: {}", index, error);
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    let seed = bot.id.as_u128() as u64 | 1;
+    let (mut sender, receiver) = bot.into_split();
+    // The frame timer does the pacing
+    sender.send_interval = Duration::ZERO;
+
+    // Either half can notice the connection is gone, count it once
+    let lost = Arc::new(AtomicBool::new(false));
+    let receiving = tokio::spawn(receive(receiver, stats.clone(), started, stop.clone(), lost.clone()));
+    if send(&mut sender, Rng(seed), &stats, started, &stop).await.is_err() {
+        drop_connection(&stats, &stop, &lost);
+    }
+    let _ = sender.close().await;
+    receiving.abort();
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn drop_connection(stats: &Stats, stop: &AtomicBool, lost: &AtomicBool) {
+    if !stop.load(Ordering::Relaxed) && !lost.swap(true, Ordering::Relaxed) {
+        stats.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn send(sender: &mut BotSender, mut rng: Rng, stats: &Stats, started: Instant, stop: &AtomicBool) -> std::io::Result<()> {
+    sender.send(&bot::costume_packet(COSTUMES[0], COSTUMES[0])).await?;
+    sender.send(&bot::game_packet(KINGDOMS[0].home_stage, 1)).await?;
+    stats.sent.fetch_add(2, Ordering::Relaxed);
+
+    let mut frames = time::interval(FRAME);
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut frame: u64 = 0;
+    while !stop.load(Ordering::Relaxed) {
+        frames.tick().await;
+        frame += 1;
+
+        // Walk in a circle so positions change like they would in game
+        let angle = frame as f32 / 60.0;
+        let position = [angle.cos() * 500.0, 0.0, angle.sin() * 500.0];
+        let mut player = bot::player_packet(position);
+        let sent_at = timestamp(started);
+        player.packet.act = (sent_at >> 16) as u16;
+        player.packet.sub_act = sent_at as u16;
+        sender.send(&player).await?;
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        stats.players_sent.fetch_add(1, Ordering::Relaxed);
+
+        if frame.is_multiple_of(CAP_EVERY_FRAMES) {
+            sender.send(&bot::cap_packet(position, frame % (CAP_EVERY_FRAMES * 10) < CAP_EVERY_FRAMES * 5)).await?;
+            stats.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        if rng.one_in(STAGE_CHANGE_FRAMES) {
+            let stage = KINGDOMS[rng.next() as usize % KINGDOMS.len()].home_stage;
+            let scenario = (rng.next() % MAX_SCENARIO as u64) as i8 + 1;
+            sender.send(&bot::change_stage_packet(stage, scenario)).await?;
+            sender.send(&bot::game_packet(stage, scenario as u8)).await?;
+            stats.sent.fetch_add(2, Ordering::Relaxed);
+        }
+        if rng.one_in(COSTUME_CHANGE_FRAMES) {
+            let costume = COSTUMES[rng.next() as usize % COSTUMES.len()];
+            sender.send(&bot::costume_packet(costume, costume)).await?;
+            stats.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+async fn receive(mut receiver: BotReceiver, stats: Arc<Stats>, started: Instant, stop: Arc<AtomicBool>, lost: Arc<AtomicBool>) {
+    loop {
+        let packet = match receiver.recv().await {
+            Ok(packet) => packet,
+            Err(_) => {
+                drop_connection(&stats, &stop, &lost);
+                return;
+            }
+        };
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_received.fetch_add((PACKET_HEADER_SIZE + packet.body.len()) as u64, Ordering::Relaxed);
+
+        if packet.packet_type() == PacketType::Player {
+            let player = packet.decode::<PlayerPacket>().packet;
+            let sent_at = ((player.act as u32) << 16) | player.sub_act as u32;
+            stats.players_received.fetch_add(1, Ordering::Relaxed);
+            stats.latencies.lock().unwrap().push(timestamp(started).wrapping_sub(sent_at));
+        }
+    }
+}
+
+/// CPU time and resident memory of a process, `None` without /proc
+fn usage(pid: Option<u32>) -> Option<Usage> {
+    let proc_dir = match pid {
+        Some(pid) => format!("/proc/{}", pid),
+        None => "/proc/self".to_string(),
+    };
+
+    // The command name can hold spaces, the fields we want come after it
+    let stat = fs::read_to_string(format!("{}/stat", proc_dir)).ok()?;
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let cpu_ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+    let status = fs::read_to_string(format!("{}/status", proc_dir)).ok()?;
+    let rss_kb = status.lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse().ok()?;
+
+    Some(Usage { cpu_ticks, rss_kb })
+}
+
+fn percentile(sorted: &[u32], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+    sorted[index] as f64 / 1000.0
+}
+
+fn latency_summary(latencies: &mut [u32]) -> String {
+    latencies.sort_unstable();
+    format!(
+        "p50 {:.2}ms p90 {:.2}ms p99 {:.2}ms max {:.2}ms",
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.9),
+        percentile(latencies, 0.99),
+        percentile(latencies, 1.0)
+    )
+}
+
+/// Received PlayerPackets as a share of what should have been relayed, every player gets everyone else's
+fn delivery(players_sent: u64, players_received: u64, players: usize) -> f64 {
+    let expected = players_sent * players.saturating_sub(1) as u64;
+    if expected == 0 {
+        return 100.0;
+    }
+    players_received as f64 * 100.0 / expected as f64
+}
+
+async fn report(options: Arc<Options>, stats: Arc<Stats>, started: Instant, stop: Arc<AtomicBool>) -> Totals {
+    let mut totals = Totals::default();
+    let mut last = Instant::now();
+    let mut last_usage = usage(options.pid);
+    let mut reports = time::interval_at(Instant::now() + options.report, options.report);
+
+    println!(
+        "{:>7} {:>7} {:>9} {:>9} {:>10} {:>8} {:>6} {:>7} {:>9}  relay latency",
+        "time", "players", "sent/s", "recv/s", "KiB/s in", "relayed", "drops", "cpu", "rss"
+    );
+    loop {
+        reports.tick().await;
+        let now = Instant::now();
+        let seconds = (now - last).as_secs_f64();
+        last = now;
+
+        let sent = stats.sent.swap(0, Ordering::Relaxed);
+        let received = stats.received.swap(0, Ordering::Relaxed);
+        let bytes_received = stats.bytes_received.swap(0, Ordering::Relaxed);
+        let players_sent = stats.players_sent.swap(0, Ordering::Relaxed);
+        let players_received = stats.players_received.swap(0, Ordering::Relaxed);
+        let mut latencies = std::mem::take(&mut *stats.latencies.lock().unwrap());
+
+        let current_usage = usage(options.pid);
+        let (cpu, rss) = match (&last_usage, &current_usage) {
+            (Some(before), Some(after)) => (
+                format!("{:.1}%", (after.cpu_ticks - before.cpu_ticks) as f64 / CLOCK_TICKS / seconds * 100.0),
+                format!("{:.1}MiB", after.rss_kb as f64 / 1024.0)
+            ),
+            _ => ("-".to_string(), "-".to_string()),
+        };
+        last_usage = current_usage;
+
+        println!(
+            "{:>6.0}s {:>7} {:>9.0} {:>9.0} {:>10.1} {:>7.1}% {:>6} {:>7} {:>9}  {}",
+            started.elapsed().as_secs_f64(),
+            stats.connected.load(Ordering::Relaxed),
+            sent as f64 / seconds,
+            received as f64 / seconds,
+            bytes_received as f64 / 1024.0 / seconds,
+            delivery(players_sent, players_received, options.players),
+            stats.dropped.load(Ordering::Relaxed),
+            cpu,
+            rss,
+            latency_summary(&mut latencies)
+        );
+
+        totals.sent += sent;
+        totals.received += received;
+        totals.bytes_received += bytes_received;
+        totals.players_sent += players_sent;
+        totals.players_received += players_received;
+        totals.latencies.append(&mut latencies);
+
+        if stop.load(Ordering::Relaxed) {
+            return totals;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => Arc::new(options),
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let address: SocketAddr = match &options.address {
+        Some(address) => match tokio::net::lookup_host(address).await.ok().and_then(|mut addresses| addresses.next()) {
+            Some(address) => address,
+            None => {
+                eprintln!("Can't resolve {}", address);
+                process::exit(1);
+            }
+        },
+        None => {
+            let server = ServerBuilder::new().bind("127.0.0.1:0").start().await.expect("Failed to bind the in-process server");
+            let address = server.local_addr();
+            println!("Started a server on {}, its CPU and memory include the simulated players", address);
+            address
+        }
+    };
+    if options.address.is_some() && options.pid.is_none() {
+        println!("No --pid, CPU and memory are this process's");
+    }
+
+    let stats = Arc::new(Stats::default());
+    let stop = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+    println!("{} players for {}s against {}", options.players, options.duration.as_secs(), address);
+
+    let players: Vec<_> = (0..options.players)
+        .map(|index| tokio::spawn(run_player(index, address, stats.clone(), started, stop.clone())))
+        .collect();
+    let reporter = tokio::spawn(report(options.clone(), stats.clone(), started, stop.clone()));
+
+    time::sleep(options.duration).await;
+    stop.store(true, Ordering::Relaxed);
+    for player in players {
+        let _ = player.await;
+    }
+    let mut totals = reporter.await.unwrap();
+
+    let seconds = options.duration.as_secs_f64();
+    println!();
+    println!("Sent {} packets ({:.0}/s), received {} ({:.0}/s, {:.1} KiB/s)",
+        totals.sent, totals.sent as f64 / seconds,
+        totals.received, totals.received as f64 / seconds,
+        totals.bytes_received as f64 / 1024.0 / seconds);
+    println!("Relayed {:.1}% of PlayerPackets, latency {}",
+        delivery(totals.players_sent, totals.players_received, options.players),
+        latency_summary(&mut totals.latencies));
+    println!("{} connections dropped, {} failed to connect",
+        stats.dropped.load(Ordering::Relaxed),
+        stats.failed.load(Ordering::Relaxed));
+}
@@ -0,0 +1,312 @@
+//! Headless game client for integration tests and load testing
+use std::{
+    io,
+    time::Duration
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        ToSocketAddrs,
+        tcp::{OwnedReadHalf, OwnedWriteHalf}
+    },
+    time::{self, Instant}
+};
+use nalgebra::Vector3;
+use uuid::Uuid;
+use crate::{
+    client::encode_frame,
+    constants::packet_to_type_map,
+    packet::{
+        PacketHeader::{PacketHeader, SIZE as PACKET_HEADER_SIZE},
+        PacketType::PacketType,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            CapPacket::CapPacket,
+            ChangeStagePacket::ChangeStagePacket,
+            ConnectPacket::{ConnectPacket, ConnectionTypes},
+            CostumePacket::CostumePacket,
+            GamePacket::GamePacket,
+            InitPacket::InitPacket,
+            PlayerPacket::PlayerPacket,
+            ShinePacket::ShinePacket
+        }
+    }
+};
+
+// How long `expect` waits for a packet
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(2);
+// The game sends at most one packet a frame
+pub const SEND_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A packet sent by the server
+pub struct Received {
+    pub header: IPacket<PacketHeader>,
+    pub body: Vec<u8>,
+}
+
+impl Received {
+    pub fn packet_type(&self) -> PacketType {
+        self.header.packet.packet_type
+    }
+
+    /// The player the packet is about, usually whoever sent it
+    pub fn sender(&self) -> Uuid {
+        self.header.packet.id
+    }
+
+    pub fn decode<T>(&self) -> IPacket<T>
+    where IPacket<T>: IPacketTrait
+    {
+        let mut buffer = [0; 1024];
+        buffer[..self.body.len()].copy_from_slice(&self.body);
+        let mut packet = IPacket::<T>::new();
+        packet.deserialize(&buffer);
+        packet
+    }
+}
+
+/// Connects like the game does, then sends whatever it is told to and collects what comes back
+pub struct Bot {
+    pub id: Uuid,
+    pub name: String,
+    // From the server's InitPacket
+    pub max_players: u16,
+    sender: BotSender,
+    receiver: BotReceiver,
+}
+
+/// Sending half of a bot, see `Bot::into_split`
+pub struct BotSender {
+    id: Uuid,
+    writer: OwnedWriteHalf,
+    // Least time between two packets
+    pub send_interval: Duration,
+    last_send: Option<Instant>,
+}
+
+/// Receiving half of a bot, see `Bot::into_split`
+pub struct BotReceiver {
+    reader: OwnedReadHalf,
+    // Bytes read but not yet split into packets
+    buffer: Vec<u8>,
+}
+
+impl Bot {
+    pub async fn connect(address: impl ToSocketAddrs, name: &str) -> io::Result<Bot> {
+        Bot::join(address, Uuid::new_v4(), name, ConnectionTypes::FirstConnection, "").await
+    }
+
+    /// Connects like a mod set up with the server password, see `whitelist.password`
+    pub async fn connect_with_password(address: impl ToSocketAddrs, name: &str, password: &str) -> io::Result<Bot> {
+        Bot::join(address, Uuid::new_v4(), name, ConnectionTypes::FirstConnection, password).await
+    }
+
+    /// Connects again as a player that was connected before
+    pub async fn reconnect(address: impl ToSocketAddrs, id: Uuid, name: &str) -> io::Result<Bot> {
+        Bot::join(address, id, name, ConnectionTypes::Reconnecting, "").await
+    }
+
+    async fn join(address: impl ToSocketAddrs, id: Uuid, name: &str, connection_type: ConnectionTypes, password: &str) -> io::Result<Bot> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+
+        let mut bot = Bot {
+            id,
+            name: name.to_string(),
+            max_players: 0,
+            sender: BotSender {
+                id,
+                writer,
+                send_interval: SEND_INTERVAL,
+                last_send: None,
+            },
+            receiver: BotReceiver {
+                reader,
+                buffer: Vec::new(),
+            },
+        };
+
+        let init = bot.expect(PacketType::Init).await?;
+        bot.max_players = init.decode::<InitPacket>().packet.max_players;
+
+        let mut connect_packet = IPacket::<ConnectPacket>::new();
+        connect_packet.packet.connection_type = connection_type;
+        connect_packet.packet.max_players = bot.max_players;
+        connect_packet.packet.client_name = name.to_string();
+        if !password.is_empty() {
+            connect_packet.set_password(password);
+        }
+        bot.send(&connect_packet).await?;
+
+        Ok(bot)
+    }
+
+    /// Sends and receives independently, e.g. from two tasks
+    pub fn into_split(self) -> (BotSender, BotReceiver) {
+        (self.sender, self.receiver)
+    }
+
+    /// Sends the packet as this player, waiting `SEND_INTERVAL` after the previous one
+    pub async fn send<T: IPacketTrait>(&mut self, packet: &T) -> io::Result<()> {
+        self.sender.send(packet).await
+    }
+
+    /// Sends the packets one after another, `interval` apart
+    pub async fn play<T: IPacketTrait>(&mut self, packets: &[T], interval: Duration) -> io::Result<()> {
+        for packet in packets {
+            self.send(packet).await?;
+            time::sleep(interval).await;
+        }
+        Ok(())
+    }
+
+    pub async fn send_position(&mut self, position: [f32; 3]) -> io::Result<()> {
+        self.send(&player_packet(position)).await
+    }
+
+    pub async fn send_stage(&mut self, stage: &str, scenario: u8) -> io::Result<()> {
+        self.send(&game_packet(stage, scenario)).await
+    }
+
+    /// The game sends its costume once the save has loaded
+    pub async fn send_costume(&mut self, body: &str, cap: &str) -> io::Result<()> {
+        self.send(&costume_packet(body, cap)).await
+    }
+
+    pub async fn send_shine(&mut self, shine_id: u32) -> io::Result<()> {
+        self.send(&shine_packet(shine_id)).await
+    }
+
+    /// The next packet, fails with `UnexpectedEof` once the server closes the connection
+    pub async fn recv(&mut self) -> io::Result<Received> {
+        self.receiver.recv().await
+    }
+
+    /// Skips packets until one matches, fails with `TimedOut` after `within`
+    pub async fn expect_where(&mut self, within: Duration, matches: impl Fn(&Received) -> bool) -> io::Result<Received> {
+        let deadline = Instant::now() + within;
+        loop {
+            match time::timeout_at(deadline, self.recv()).await {
+                Ok(Ok(packet)) if matches(&packet) => return Ok(packet),
+                Ok(Ok(_)) => continue,
+                Ok(Err(error)) => return Err(error),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} didn't receive the expected packet", self.name))),
+            }
+        }
+    }
+
+    pub async fn expect(&mut self, packet_type: PacketType) -> io::Result<Received> {
+        self.expect_where(EXPECT_TIMEOUT, |packet| packet.packet_type() == packet_type).await
+    }
+
+    pub async fn expect_from(&mut self, packet_type: PacketType, sender: Uuid) -> io::Result<Received> {
+        self.expect_where(EXPECT_TIMEOUT, |packet| packet.packet_type() == packet_type && packet.sender() == sender).await
+    }
+
+    /// Fails if a packet of this type arrives within `within`
+    pub async fn expect_none(&mut self, packet_type: PacketType, within: Duration) -> io::Result<()> {
+        match self.expect_where(within, |packet| packet.packet_type() == packet_type).await {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} received an unexpected packet", self.name))),
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Closes the connection like the game does when it quits
+    pub async fn close(self) -> io::Result<()> {
+        self.sender.close().await
+    }
+}
+
+impl BotSender {
+    pub async fn send<T: IPacketTrait>(&mut self, packet: &T) -> io::Result<()> {
+        if let Some(last_send) = self.last_send {
+            time::sleep_until(last_send + self.send_interval).await;
+        }
+
+        let mut packet_header = IPacket::<PacketHeader>::new();
+        packet_header.packet.id = self.id;
+        packet_header.packet.packet_type = packet_to_type_map(packet.get_name());
+        packet_header.packet.packet_size = packet.get_size().to_owned() as i16;
+
+        self.writer.write_all(&encode_frame(&packet_header, packet)).await?;
+        self.last_send = Some(Instant::now());
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+}
+
+impl BotReceiver {
+    pub async fn recv(&mut self) -> io::Result<Received> {
+        loop {
+            if self.buffer.len() >= PACKET_HEADER_SIZE {
+                let packet_size = i16::from_le_bytes([self.buffer[18], self.buffer[19]]);
+                if packet_size < 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("negative packet size {}", packet_size)));
+                }
+
+                let frame_size = PACKET_HEADER_SIZE + packet_size as usize;
+                if self.buffer.len() >= frame_size {
+                    let frame: Vec<u8> = self.buffer.drain(..frame_size).collect();
+                    let mut header = IPacket::<PacketHeader>::new();
+                    header.deserialize(&frame[..PACKET_HEADER_SIZE]);
+                    return Ok(Received {
+                        header,
+                        body: frame[PACKET_HEADER_SIZE..].to_vec(),
+                    });
+                }
+            }
+
+            let mut chunk = [0; 1024];
+            match self.reader.read(&mut chunk).await? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection")),
+                size => self.buffer.extend_from_slice(&chunk[..size]),
+            }
+        }
+    }
+}
+
+pub fn player_packet(position: [f32; 3]) -> IPacket<PlayerPacket> {
+    let mut packet = IPacket::<PlayerPacket>::new();
+    packet.packet.position = Vector3::new(position[0], position[1], position[2]);
+    packet
+}
+
+pub fn cap_packet(position: [f32; 3], cap_out: bool) -> IPacket<CapPacket> {
+    let mut packet = IPacket::<CapPacket>::new();
+    packet.packet.position = Vector3::new(position[0], position[1], position[2]);
+    packet.packet.cap_out = cap_out;
+    packet
+}
+
+pub fn change_stage_packet(stage: &str, scenario: i8) -> IPacket<ChangeStagePacket> {
+    let mut packet = IPacket::<ChangeStagePacket>::new();
+    packet.packet.stage = stage.to_string();
+    packet.packet.scenario = scenario;
+    packet
+}
+
+pub fn game_packet(stage: &str, scenario: u8) -> IPacket<GamePacket> {
+    let mut packet = IPacket::<GamePacket>::new();
+    packet.packet.stage = stage.to_string();
+    packet.packet.scenario_num = scenario;
+    packet
+}
+
+pub fn costume_packet(body: &str, cap: &str) -> IPacket<CostumePacket> {
+    let mut packet = IPacket::<CostumePacket>::new();
+    packet.packet.body_name = body.to_string();
+    packet.packet.cap_name = cap.to_string();
+    packet
+}
+
+pub fn shine_packet(shine_id: u32) -> IPacket<ShinePacket> {
+    let mut packet = IPacket::<ShinePacket>::new();
+    packet.packet.shine_id = shine_id;
+    packet
+}
@@ -0,0 +1,250 @@
+//! Runs a server inside another program. [`ServerBuilder`] sets it up, [`ServerHandle`] controls it once it's running
+use std::{
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc
+};
+use tokio::{
+    io,
+    net::TcpListener,
+    sync::{RwLock, broadcast, oneshot},
+    task::JoinHandle
+};
+use tracing::warn;
+use crate::{
+    console::Console,
+    events::ServerEvent,
+    hooks::{PacketHook, PlayerView},
+    reload::{SettingsFile, SettingsReloader},
+    server::{Server, ServerWrapper},
+    settings::{Settings, SettingsOverrides},
+    stages::KEEP_SCENARIO
+};
+
+type Subscriber = Box<dyn FnMut(ServerEvent) + Send>;
+
+/// Collects everything a server needs, nothing runs until `start`
+pub struct ServerBuilder {
+    settings: Settings,
+    settings_file: Option<PathBuf>,
+    // The settings were already read from `settings_file`
+    file_read: bool,
+    overrides: SettingsOverrides,
+    address: Option<String>,
+    listener: Option<TcpListener>,
+    hooks: Vec<Arc<dyn PacketHook>>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    /// `Settings::defaults`, listening where `server.address` and `server.port` say
+    pub fn new() -> Self {
+        ServerBuilder {
+            settings: Settings::defaults(),
+            settings_file: None,
+            file_read: false,
+            overrides: SettingsOverrides::default(),
+            address: None,
+            listener: None,
+            hooks: Vec::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Reads the settings from a file, which is reloaded when it changes, see `SettingsReloader`.
+    /// A missing file is created with the settings given to `settings`, or the defaults
+    pub fn settings_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings_file = Some(path.into());
+        self
+    }
+
+    /// Settings already read from `path` by `Settings::load` with the same overrides, so `start` doesn't read it again.
+    /// The file is still reloaded when it changes
+    pub fn loaded_settings_file(mut self, path: impl Into<PathBuf>, settings: Settings) -> Self {
+        self.settings = settings;
+        self.settings_file = Some(path.into());
+        self.file_read = true;
+        self
+    }
+
+    /// Applied over the settings, and over the settings file again whenever it's reloaded
+    pub fn overrides(mut self, overrides: SettingsOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Bound by `start` instead of `server.address` and `server.port`, port 0 picks a free one
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Serves an already bound listener, the address is ignored
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Runs after the built in hooks, in the order they were added
+    pub fn hook(mut self, hook: impl PacketHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Called with every event on its own task, so it can block without holding up the server.
+    /// Subscribed before the first connection, it runs until the server is dropped
+    pub fn on_event(mut self, subscriber: impl FnMut(ServerEvent) + Send + 'static) -> Self {
+        self.subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    /// Reads the settings file if there is one, binds the address unless a listener was given
+    /// and starts serving in the background. Settings that don't validate are refused
+    pub async fn start(self) -> io::Result<ServerHandle> {
+        let mut settings = match &self.settings_file {
+            Some(path) if !self.file_read && path.exists() => Settings::read(path, &self.overrides)?,
+            Some(path) if !self.file_read => {
+                fs::write(path, serde_json::to_string_pretty(&self.settings)?)?;
+                self.settings
+            },
+            _ => self.settings,
+        };
+        // Settings that were read have them already, applying them again changes nothing
+        self.overrides.apply(&mut settings);
+        settings.validate().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let listener = match (self.listener, self.address) {
+            (Some(listener), _) => listener,
+            (None, Some(address)) => TcpListener::bind(address).await?,
+            (None, None) => TcpListener::bind(settings.server.bind_address()?).await?,
+        };
+        let local_addr = listener.local_addr()?;
+
+        let settings_file = self.settings_file.map(|path| Arc::new(SettingsFile::new(path, self.overrides, &settings)));
+        let mut server = Server::new(settings);
+        server.settings_file = settings_file;
+        for hook in self.hooks {
+            server.hooks.register(hook);
+        }
+        for subscriber in self.subscribers {
+            tokio::spawn(ServerBuilder::deliver(server.events.subscribe(), subscriber));
+        }
+
+        let server = Arc::new(RwLock::new(server));
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(ServerHandle::run(server.clone(), listener, stop));
+        Ok(ServerHandle {
+            server,
+            local_addr,
+            shutdown: Some(shutdown),
+            task: Some(task),
+        })
+    }
+
+    async fn deliver(mut events: broadcast::Receiver<ServerEvent>, mut subscriber: Subscriber) {
+        loop {
+            match events.recv().await {
+                Ok(event) => subscriber(event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Event subscriber fell behind and skipped events");
+                },
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// A running server. Dropping the handle leaves it running, like dropping a `JoinHandle`
+pub struct ServerHandle {
+    server: Arc<RwLock<Server>>,
+    local_addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    // Taken once the server has stopped
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ServerHandle {
+    async fn run(server: Arc<RwLock<Server>>, listener: TcpListener, stop: oneshot::Receiver<()>) -> io::Result<()> {
+        // A dropped handle closes the channel without sending, which doesn't count as a shutdown
+        let result = tokio::select! {
+            result = ServerWrapper::start(server.clone(), listener) => result,
+            Ok(()) = stop => Ok(()),
+        };
+        ServerWrapper::shutdown(server).await;
+        result
+    }
+
+    /// Where the server is listening, the port is filled in when binding port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The server's state, for anything the handle doesn't cover
+    pub fn server(&self) -> Arc<RwLock<Server>> {
+        self.server.clone()
+    }
+
+    /// Everyone who is connected right now
+    pub async fn players(&self) -> Vec<PlayerView> {
+        let players = self.server.read().await.players.clone();
+        let mut views = Vec::new();
+        for player in players.connected() {
+            views.push(PlayerView::of(&*player.client.read().await));
+        }
+        views
+    }
+
+    /// Runs a console command and returns what the console would print
+    pub async fn execute(&self, command: &str) -> Result<String, String> {
+        Console::execute(self.server.clone(), command).await
+    }
+
+    /// Sends players matching the selector to a stage or kingdom alias, `None` keeps their scenario
+    pub async fn warp(&self, player: &str, target: &str, scenario: Option<i8>) -> Result<String, String> {
+        Console::warp(self.server.clone(), player, target, scenario.unwrap_or(KEEP_SCENARIO), "").await
+    }
+
+    /// Makes players matching the selector seekers or hiders
+    pub async fn tag(&self, player: &str, is_it: bool) -> Result<String, String> {
+        Console::tag(self.server.clone(), player, is_it).await
+    }
+
+    pub async fn kick(&self, player: &str) -> Result<String, String> {
+        Console::kick(self.server.clone(), player).await
+    }
+
+    /// Reads the settings file now instead of waiting for the next check, see `SettingsReloader::reload`
+    pub async fn reload_settings(&self) -> Result<String, String> {
+        SettingsReloader::reload(self.server.clone()).await
+    }
+
+    /// Waits for the server to stop, before a shutdown that only happens when accepting connections fails
+    pub async fn wait(&mut self) -> io::Result<()> {
+        let Some(task) = self.task.as_mut() else {
+            return Ok(());
+        };
+        let result = task.await;
+        self.task = None;
+        result.unwrap_or_else(|error| Err(io::Error::other(error)))
+    }
+
+    /// Stops accepting connections and the background tasks, then disconnects every player
+    /// and saves state, see `ServerWrapper::shutdown`
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.wait().await
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    sync::{
+        OnceLock,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc
+    },
+    thread,
+    time::Instant
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::{
+    packet::framing::MAX_FRAME_SIZE,
+    settings::CaptureTable
+};
+
+/// Capture files start with the magic and a little endian version, followed by records:
+/// micros since the capture started (u64), direction (u8), connection (u32), client uuid (16 bytes),
+/// frame length (u32), then the frame exactly as it was read from or queued for the socket
+pub const MAGIC: &[u8; 6] = b"SMOCAP";
+pub const VERSION: u16 = 1;
+
+/// Inbound records are single socket reads, outbound ones can be a tick's batch of frames.
+/// Larger records aren't written, and reading one fails instead of allocating whatever the file says
+pub const MAX_RECORD_SIZE: usize = 64 * MAX_FRAME_SIZE;
+
+// Records waiting for the writer thread, more are dropped like a full client send queue
+const RECORD_QUEUE_SIZE: usize = 16 * 1024;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    // The connection ended, the frame is empty
+    Closed,
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = io::Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            2 => Ok(Direction::Closed),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown direction {}", v))),
+        }
+    }
+}
+
+pub struct Record {
+    pub micros: u64,
+    pub direction: Direction,
+    // Numbered in accept order, the uuid is random until the ConnectPacket arrives
+    pub connection: u32,
+    pub id: Uuid,
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.micros.to_le_bytes())?;
+        out.write_all(&[self.direction as u8])?;
+        out.write_all(&self.connection.to_le_bytes())?;
+        out.write_all(self.id.as_bytes())?;
+        out.write_all(&(self.frame.len() as u32).to_le_bytes())?;
+        out.write_all(&self.frame)
+    }
+
+    /// `None` at the end of the file
+    pub fn read_from(input: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut micros = [0; 8];
+        match input.read_exact(&mut micros) {
+            Ok(()) => {},
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+
+        let mut fixed = [0; 1 + 4 + 16 + 4];
+        input.read_exact(&mut fixed)?;
+        let direction = Direction::try_from(fixed[0])?;
+        let connection = u32::from_le_bytes(fixed[1..5].try_into().unwrap());
+        let id = Uuid::from_bytes(fixed[5..21].try_into().unwrap());
+        let length = u32::from_le_bytes(fixed[21..25].try_into().unwrap()) as usize;
+        if length > MAX_RECORD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record of {} bytes, the most is {}", length, MAX_RECORD_SIZE)));
+        }
+
+        let mut frame = vec![0; length];
+        input.read_exact(&mut frame)?;
+
+        Ok(Some(Record {
+            micros: u64::from_le_bytes(micros),
+            direction,
+            connection,
+            id,
+            frame,
+        }))
+    }
+}
+
+/// Every record in a capture file, in the order they were written
+pub fn load(file_name: &str) -> io::Result<Vec<Record>> {
+    read_records(&mut BufReader::new(File::open(file_name)?))
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", file_name, error)))
+}
+
+/// Reads a whole capture, starting with the magic
+pub fn read_records(input: &mut impl Read) -> io::Result<Vec<Record>> {
+    let mut magic = [0; 6];
+    input.read_exact(&mut magic)?;
+    let mut version = [0; 2];
+    input.read_exact(&mut version)?;
+    if &magic != MAGIC || u16::from_le_bytes(version) != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("not a version {} capture", VERSION)));
+    }
+
+    let mut records = Vec::new();
+    while let Some(record) = Record::read_from(input)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes records on its own thread so recording never waits on the disk
+struct Recorder {
+    started: Instant,
+    sender: mpsc::SyncSender<Record>,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+// Records left out because the queue was full or they were too large, logged by the writer thread
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Starts recording to `CaptureTable::file_name` when capturing is enabled, the file is replaced
+pub fn start(settings: &CaptureTable) -> io::Result<()> {
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mut out = BufWriter::new(File::create(&settings.file_name)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+
+    let (sender, receiver) = mpsc::sync_channel::<Record>(RECORD_QUEUE_SIZE);
+    let file_name = settings.file_name.to_string();
+    thread::spawn(move || {
+        while let Ok(record) = receiver.recv() {
+            let mut result = record.write_to(&mut out);
+            // Flush once the queue is empty rather than on every frame
+            while let (Ok(()), Ok(record)) = (&result, receiver.try_recv()) {
+                result = record.write_to(&mut out);
+            }
+            if let Err(error) = result.and_then(|_| out.flush()) {
+                error!(file_name, %error, "Capture stopped");
+                return;
+            }
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!(file_name, dropped, "Capture dropped records, the disk can't keep up or they were too large");
+            }
+        }
+    });
+
+    if RECORDER.set(Recorder { started: Instant::now(), sender }).is_ok() {
+        info!(file_name = %settings.file_name, "Capturing packets");
+    }
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    RECORDER.get().is_some()
+}
+
+pub fn next_connection() -> u32 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Does nothing unless `start` enabled capturing
+pub fn record(direction: Direction, connection: u32, id: Uuid, frame: &[u8]) {
+    if let Some(recorder) = RECORDER.get() {
+        if frame.len() > MAX_RECORD_SIZE {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let record = Record {
+            micros: recorder.started.elapsed().as_micros() as u64,
+            direction,
+            connection,
+            id,
+            frame: frame.to_vec(),
+        };
+        if let Err(mpsc::TrySendError::Full(_)) = recorder.sender.try_send(record) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
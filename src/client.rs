@@ -1,17 +1,22 @@
-
+//! One game connection, what the server knows about the player and the frames waiting to be written to it
 use std::{
     hash::{Hash, Hasher},
     collections::hash_map::DefaultHasher,
+    net::SocketAddr,
     sync::{
-        Arc, 
-        // Mutex
+        Arc,
+        atomic::AtomicBool
     }
 };
 use async_trait::async_trait;
 use tokio::{
-    sync::Mutex,
-    net::TcpStream,
-    io::AsyncWriteExt
+    sync::mpsc::{
+        channel,
+        error::TrySendError,
+        Sender,
+        Receiver
+    },
+    io::{AsyncWrite, AsyncWriteExt}
 };
 use uuid::Uuid;
 use chrono::{
@@ -22,8 +27,18 @@ use crate::{
     packet::{packets::{
         GamePacket::{GamePacket},
         CostumePacket::CostumePacket,
+        PlayerPacket::PlayerPacket,
+        CapPacket::CapPacket,
         IPacket::{IPacketTrait, IPacket}
-    }, PacketHeader::PacketHeader}
+    }, PacketHeader::PacketHeader},
+    capture::{self, Direction},
+    metrics::METRICS,
+    settings::RelayedScenario,
+    smoothing::{
+        MotionHistory,
+        HISTORY_SIZE
+    },
+    stages::Kingdom
 };
 
 pub struct Time {
@@ -36,25 +51,43 @@ pub struct Metadata {
     pub shine_sync: Vec<usize>,
     pub loaded_save: bool,
     pub scenario: u8,
+    pub relayed_scenario: RelayedScenario,
+    // Last kingdom whose home stage was entered, sub areas keep it
+    pub kingdom: Option<Kingdom>,
     pub is_2d: bool,
     pub speedrun: bool,
     pub last_game_packet: Option<IPacket<GamePacket>>,
+    pub motion: MotionHistory,
+    // Newest packets waiting for the next tick when batching is enabled
+    pub pending_player: Option<IPacket<PlayerPacket>>,
+    pub pending_cap: Option<IPacket<CapPacket>>,
     pub seeking: bool,
     pub time: Time,
 }
 
+/// A connected game, the id and name come from its ConnectPacket
 pub struct Client {
     pub metadata: Metadata,
-    pub connected: bool,
+    // Shared with the player registry, see `PlayerRegistry::disconnect`
+    pub connected: Arc<AtomicBool>,
     pub current_costume: Option<IPacket<CostumePacket>>,
     pub name: String,
     pub id: Uuid,
-    pub socket: Arc<Mutex<TcpStream>>,
+    // Sent in the ConnectPacket, kept so `allowed_players` can be checked again when it changes
+    pub password: String,
+    pub address: Option<SocketAddr>,
+    // Accept order, identifies the connection in packet captures
+    pub connection: u32,
+    // Frames queued for the writer task
+    pub outgoing: Sender<Vec<u8>>,
 }
 
+// Frames a client can fall behind by before it is considered disconnected
+pub const SEND_QUEUE_SIZE: usize = 1024;
+
 #[async_trait]
 pub trait ClientTraits {
-    fn new(socket: Arc<Mutex<TcpStream>>) -> Client;
+    fn new(outgoing: Sender<Vec<u8>>) -> Self;
     fn get_hash_code(&self) -> u64;
 }
 
@@ -62,21 +95,23 @@ impl PartialEq for Client {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
-    fn ne(&self, other: &Self) -> bool {
-        self.id != other.id
-    }
 }
 
 impl ClientTraits for Client {
-    fn new(socket: Arc<Mutex<TcpStream>>) -> Client {
+    fn new(outgoing: Sender<Vec<u8>>) -> Client {
         Client {
             metadata: Metadata {
                 shine_sync: vec![],
                 loaded_save: false, 
                 scenario: 200, 
+                relayed_scenario: RelayedScenario::Own(200),
+                kingdom: None,
                 is_2d: false, 
                 speedrun: false, 
                 last_game_packet: None, 
+                motion: MotionHistory::new(HISTORY_SIZE),
+                pending_player: None,
+                pending_cap: None,
                 seeking: false, 
                 time: Time {
                     minutes: 0,
@@ -84,11 +119,14 @@ impl ClientTraits for Client {
                     when: Utc::now(),
                 }
             },
-            socket: socket,
-            connected: false,
+            outgoing,
+            connected: Arc::new(AtomicBool::new(false)),
             current_costume: None,
             name: "".to_string(),
             id: Uuid::new_v4(),
+            password: String::new(),
+            address: None,
+            connection: 0,
             // server: &server,
         }
     }
@@ -96,44 +134,69 @@ impl ClientTraits for Client {
     fn get_hash_code(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.id.hash(&mut hasher);
-        return hasher.finish();
+        hasher.finish()
+    }
+}
+
+/// Header followed by the packet, as it goes over the wire
+pub fn encode_frame<T: IPacketTrait>(packet_header: &IPacket<PacketHeader>, packet: &T) -> Vec<u8> {
+    let packet_header_size: usize = packet_header.packet_size;
+    let packet_size: usize = packet_header.packet.packet_size as usize;
+
+    let mut frame: Vec<u8> = Vec::with_capacity(packet_header_size + packet_size);
+    frame.extend_from_slice(&packet_header.serialize()[..packet_header_size]);
+    frame.extend_from_slice(&packet.serialize()[..packet_size]);
+    frame
+}
+
+/// Queues the empty frame that makes the writer task close the socket and stop.
+/// A full queue waits for room, so the writer never outlives the connection
+pub fn close_queue(outgoing: &Sender<Vec<u8>>) {
+    if let Err(TrySendError::Full(_)) = outgoing.try_send(Vec::new()) {
+        let outgoing = outgoing.clone();
+        tokio::spawn(async move {
+            let _ = outgoing.send(Vec::new()).await;
+        });
     }
 }
 
 impl Client {
+    /// Writes queued frames to the socket until it fails or every sender is dropped
+    pub fn spawn_writer<W: AsyncWrite + Unpin + Send + 'static>(mut socket: W) -> Sender<Vec<u8>> {
+        let (sender, mut receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel(SEND_QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                // Queued by `close`
+                if frame.is_empty() {
+                    let _ = socket.shutdown().await;
+                    break;
+                }
+                if socket.write_all(&frame).await.is_err() {
+                    break;
+                }
+                METRICS.frame_out(&frame);
+            }
+        });
+        sender
+    }
+
     pub async fn send<T: IPacketTrait>(&self, packet_header: &IPacket<PacketHeader>, packet: &T) -> bool
     {
-        let packet_header_size: usize = packet_header.packet_size as usize;
-        let packet_size: usize = packet_header.packet.packet_size as usize;
-
-
-        let mut raw_data: [u8; 1024] = [0; 1024];
-        raw_data[..packet_header_size].copy_from_slice(&packet_header.serialize()[..packet_header_size]);
-        raw_data[packet_header_size..(packet_header_size + packet_size)].copy_from_slice(
-            &packet.serialize()[..packet_size]
-        );
+        let frame = encode_frame(packet_header, packet);
+        self.send_raw_data(&frame, frame.len()).await
+    }
 
-        // println!("It's sending");
-        // println!("{:?}", &raw_data[..(packet_header_size + packet_size)]);
-        let result = self.socket.lock().await
-            .write_all(&raw_data[..(packet_header_size + packet_size)])
-            .await;
-        
-        return match result {
-            Ok(_) => true,
-            Err(_) => false,
-        }
+    /// Closes the socket once everything queued before it has been written
+    pub fn close(&self) {
+        close_queue(&self.outgoing);
     }
 
+    /// Fails when the writer task has stopped or the client fell too far behind
     pub async fn send_raw_data(&self, data: &[u8], size: usize) -> bool {
-        
-        let result = self.socket.lock().await
-            .write_all(&data[..size])
-            .await;
-        
-        return match result {
-            Ok(_) => true,
-            Err(_) => false,
+        let sent = self.outgoing.try_send(data[..size].to_vec()).is_ok();
+        if sent {
+            capture::record(Direction::Outbound, self.connection, self.id, &data[..size]);
         }
+        sent
     }
-}
\ No newline at end of file
+}
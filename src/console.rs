@@ -0,0 +1,197 @@
+use std::{
+    io::{self, BufRead},
+    sync::Arc,
+    thread
+};
+use tokio::sync::{RwLock, mpsc};
+use crate::{
+    reload::SettingsReloader,
+    server::{
+        Server,
+        ServerWrapper
+    },
+    stages::{
+        self,
+        KINGDOMS,
+        KEEP_SCENARIO
+    }
+};
+
+const HELP: &str = "Commands:
+    help                                         Show this message
+    list                                         List connected players
+    stages                                       List kingdoms and their aliases
+    warp <player|*> <stage|alias> [scenario] [id]  Send players to a stage
+    tag <player|*> <seeker|hider>                Set players' hide and seek role
+    kick <player|*>                              Disconnect players
+    allow <uuid|name>                            Let a player in while allowed_players is on, until a restart
+    disallow <uuid|name>                         Undo allow
+    settings reload                              Apply changes to the settings file now
+    scripts reload                               Reload game mode scripts, needs the scripting feature";
+
+/// Commands typed into the server's stdin, `ServerHandle` runs the same ones
+pub struct Console;
+
+impl Console {
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        // Read on a plain thread, a read blocked on tokio's stdin keeps the runtime from shutting down
+        let (sender, mut lines) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        while let Some(line) = lines.recv().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match Console::execute(server.clone(), &line).await {
+                Ok(message) => println!("{}", message),
+                Err(message) => println!("Error: {}", message),
+            }
+        }
+    }
+
+    pub async fn execute(server: Arc<RwLock<Server>>, line: &str) -> Result<String, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["help"] => Ok(HELP.to_string()),
+            ["list"] => Console::list(server).await,
+            ["stages"] => Ok(Console::stages()),
+            ["warp", player, target, rest @ ..] if rest.len() <= 2 => {
+                let scenario = match rest.first() {
+                    Some(scenario) => scenario.parse::<i8>().map_err(|_| format!("Invalid scenario {:?}", scenario))?,
+                    None => KEEP_SCENARIO,
+                };
+                let id = rest.get(1).unwrap_or(&"");
+                Console::warp(server, player, target, scenario, id).await
+            },
+            ["tag", player, role] => {
+                let is_it = match *role {
+                    "seeker" | "it" => true,
+                    "hider" => false,
+                    _ => return Err(format!("Unknown role {:?}, expected seeker or hider", role)),
+                };
+                Console::tag(server, player, is_it).await
+            },
+            ["kick", player] => Console::kick(server, player).await,
+            ["allow", member] => Console::allow(server, member).await,
+            ["disallow", member] => Console::disallow(server, member).await,
+            ["settings", "reload"] => SettingsReloader::reload(server).await,
+            #[cfg(feature = "scripting")]
+            ["scripts", "reload"] => Ok(server.read().await.scripts.reload()),
+            [command, ..] => Err(format!("Unknown or malformed command {:?}, try `help`", command)),
+            [] => Err("Empty command".to_string()),
+        }
+    }
+
+    pub async fn list(server: Arc<RwLock<Server>>) -> Result<String, String> {
+        let mut lines: Vec<String> = vec![];
+        for c in ServerWrapper::find_clients(server.clone(), "*").await {
+            let client = c.read().await;
+            let stage = match &client.metadata.last_game_packet {
+                Some(game_packet) => stages::friendly_name(&game_packet.packet.stage),
+                None => "unknown stage".to_string(),
+            };
+            lines.push(format!(
+                "{} ({}) - {} scenario {} (relayed as {})",
+                client.name,
+                client.id,
+                stage,
+                client.metadata.scenario,
+                client.metadata.relayed_scenario
+            ));
+        }
+
+        if lines.is_empty() {
+            return Ok("No players connected".to_string());
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn stages() -> String {
+        KINGDOMS.iter()
+            .map(|k| format!("{}: {} [{}]", k.name, k.home_stage, k.aliases.join(", ")))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub async fn warp(server: Arc<RwLock<Server>>, player: &str, target: &str, scenario: i8, id: &str) -> Result<String, String> {
+        let (stage, scenario) = stages::resolve_warp_target(target, scenario)?;
+
+        let clients = ServerWrapper::find_clients(server.clone(), player).await;
+        if clients.is_empty() {
+            return Err(format!("No connected player matches {:?}", player));
+        }
+
+        let mut warped: Vec<String> = vec![];
+        for c in clients {
+            if ServerWrapper::warp(server.clone(), c.clone(), &stage, id, scenario).await {
+                warped.push(c.read().await.name.to_string());
+            }
+        }
+
+        Ok(format!("Sent {} to {} scenario {}", warped.join(", "), stages::friendly_name(&stage), scenario))
+    }
+
+    pub async fn tag(server: Arc<RwLock<Server>>, player: &str, is_it: bool) -> Result<String, String> {
+        let clients = ServerWrapper::find_clients(server.clone(), player).await;
+        if clients.is_empty() {
+            return Err(format!("No connected player matches {:?}", player));
+        }
+
+        let mut tagged: Vec<String> = vec![];
+        for c in clients {
+            if ServerWrapper::send_tag_state(server.clone(), c.clone(), is_it).await {
+                tagged.push(c.read().await.name.to_string());
+            }
+        }
+
+        Ok(format!("Made {} {}", tagged.join(", "), if is_it { "seekers" } else { "hiders" }))
+    }
+
+    pub async fn kick(server: Arc<RwLock<Server>>, player: &str) -> Result<String, String> {
+        let clients = ServerWrapper::find_clients(server.clone(), player).await;
+        if clients.is_empty() {
+            return Err(format!("No connected player matches {:?}", player));
+        }
+
+        let mut kicked: Vec<String> = vec![];
+        for c in clients {
+            ServerWrapper::kick(server.clone(), c.clone()).await;
+            kicked.push(c.read().await.name.to_string());
+        }
+
+        Ok(format!("Kicked {}", kicked.join(", ")))
+    }
+
+    /// Adds to `Server::guests`, the settings file isn't touched
+    pub async fn allow(server: Arc<RwLock<Server>>, member: &str) -> Result<String, String> {
+        let mut s = server.write().await;
+        if s.guests.iter().any(|guest| guest.eq_ignore_ascii_case(member)) {
+            return Err(format!("{} is already allowed in", member));
+        }
+        s.guests.push(member.to_string());
+
+        let mut message = format!("{} can join until the server restarts", member);
+        if !s.settings.allowed_players.enabled {
+            message += ", though allowed_players isn't enabled so everyone can";
+        }
+        Ok(message)
+    }
+
+    /// Only removes players added with `allow`, connected players stay connected
+    pub async fn disallow(server: Arc<RwLock<Server>>, member: &str) -> Result<String, String> {
+        let mut s = server.write().await;
+        let count = s.guests.len();
+        s.guests.retain(|guest| !guest.eq_ignore_ascii_case(member));
+        if s.guests.len() == count {
+            return Err(format!("{} wasn't allowed in from the console", member));
+        }
+        Ok(format!("{} can't join anymore unless allowed_players lists them", member))
+    }
+}
@@ -2,8 +2,6 @@ use crate::packet::{
     PacketType::PacketType,
 };
 
-pub const COSTUME_NAME_SIZE: usize = 0x20;
-
 pub fn packet_to_type_map(key: &str) -> PacketType {
     match key {
         "CapPacket" => PacketType::Cap,
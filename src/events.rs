@@ -0,0 +1,75 @@
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use uuid::Uuid;
+use crate::{
+    registry::DisconnectReason,
+    stages::Kingdom
+};
+
+// Subscribers that fall further behind than this skip the oldest events
+const EVENT_QUEUE_SIZE: usize = 256;
+
+// Not every field is read by the built in subscribers
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    PlayerJoined { id: Uuid, name: String, slot: usize, reconnect: bool },
+    PlayerLeft { id: Uuid, name: String, slot: usize, reason: DisconnectReason },
+    StageChanged { id: Uuid, stage: String, scenario: u8, is_2d: bool, kingdom: Option<Kingdom> },
+    // Only published once the player's save has loaded, earlier ShinePackets are the save being replayed
+    ShineCollected { id: Uuid, shine_id: u32 },
+    CostumeChanged { id: Uuid, body: String, cap: String },
+    CaptureChanged { id: Uuid, model: String },
+    TagStateChanged { id: Uuid, is_it: bool },
+    // A hider became a seeker
+    Caught { id: Uuid },
+}
+
+/// Lets subsystems react to what players do without touching the packet handler.
+/// Publishing never blocks, events are dropped when nobody is subscribed.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        EventBus {
+            sender,
+        }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Logs joins and leaves, everything else is already logged by the packet handler
+    pub async fn log(mut events: broadcast::Receiver<ServerEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(ServerEvent::PlayerJoined { id, name, slot, reconnect }) => {
+                    info!(%name, %id, slot, "{}", if reconnect { "Reconnected" } else { "Joined" });
+                },
+                Ok(ServerEvent::PlayerLeft { id, name, slot, reason }) => {
+                    info!(%name, %id, slot, reason = reason.as_str(), "Left");
+                },
+                Ok(_) => {},
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Event log fell behind and skipped events");
+                },
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
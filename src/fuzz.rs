@@ -0,0 +1,81 @@
+//! Entry points for the fuzz targets in `fuzz/`, `cargo test` also runs them over `fuzz/regressions`
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock}
+};
+use tokio::{
+    io,
+    runtime::{Builder, Runtime},
+    sync::RwLock
+};
+use crate::{
+    packet::{
+        PacketHeader::PacketHeader,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            CapPacket::CapPacket,
+            CapturePacket::CapturePacket,
+            ChangeStagePacket::ChangeStagePacket,
+            ConnectPacket::ConnectPacket,
+            CostumePacket::CostumePacket,
+            DisconnectPacket::DisconnectPacket,
+            GamePacket::GamePacket,
+            InitPacket::InitPacket,
+            PlayerPacket::PlayerPacket,
+            ShinePacket::ShinePacket,
+            TagPacket::TagPacket,
+            UnhandledPacket::UnhandledPacket
+        }
+    },
+    server::{Server, ServerWrapper},
+    settings::Settings
+};
+
+/// Every fuzz target, named like its file in `fuzz/fuzz_targets` and its directory in `fuzz/regressions`
+pub const TARGETS: [&str; 14] = [
+    "cap_packet", "capture_packet", "change_stage_packet", "connect_packet", "costume_packet",
+    "disconnect_packet", "game_packet", "init_packet", "player_packet", "shine_packet",
+    "tag_packet", "unhandled_packet", "packet_header", "stream",
+];
+
+/// Decodes a packet body then encodes it again
+pub fn packet<T>(data: &[u8])
+where IPacket<T>: IPacketTrait
+{
+    let mut packet = IPacket::<T>::new();
+    packet.deserialize(data);
+    packet.serialize();
+}
+
+/// Feeds the bytes to the server as everything one client sent, then closes the connection
+pub fn stream(data: &[u8]) {
+    // Building a runtime takes longer than most inputs
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    let runtime = RUNTIME.get_or_init(|| Builder::new_current_thread().enable_all().build().unwrap());
+
+    let server = Arc::new(RwLock::new(Server::new(Settings::defaults())));
+    let address = SocketAddr::from(([127, 0, 0, 1], 0));
+    runtime.block_on(ServerWrapper::handle_connection(server, data, io::sink(), address, 0));
+}
+
+/// Runs a target by name, `false` if there's no such target
+pub fn run(target: &str, data: &[u8]) -> bool {
+    match target {
+        "cap_packet" => packet::<CapPacket>(data),
+        "capture_packet" => packet::<CapturePacket>(data),
+        "change_stage_packet" => packet::<ChangeStagePacket>(data),
+        "connect_packet" => packet::<ConnectPacket>(data),
+        "costume_packet" => packet::<CostumePacket>(data),
+        "disconnect_packet" => packet::<DisconnectPacket>(data),
+        "game_packet" => packet::<GamePacket>(data),
+        "init_packet" => packet::<InitPacket>(data),
+        "player_packet" => packet::<PlayerPacket>(data),
+        "shine_packet" => packet::<ShinePacket>(data),
+        "tag_packet" => packet::<TagPacket>(data),
+        "unhandled_packet" => packet::<UnhandledPacket>(data),
+        "packet_header" => packet::<PacketHeader>(data),
+        "stream" => stream(data),
+        _ => return false,
+    }
+    true
+}
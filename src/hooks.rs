@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+use uuid::Uuid;
+use tracing::debug;
+use crate::{
+    client::Client,
+    packet::{
+        PacketHeader::PacketHeader,
+        PacketType::PacketType,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            GamePacket::GamePacket,
+            PlayerPacket::PlayerPacket
+        }
+    },
+    settings::Settings,
+    stages::Kingdom,
+    transform::ViewTransform
+};
+
+/// What a hook knows about a player, copied out of the client so hooks never lock it
+#[derive(Clone, Debug)]
+pub struct PlayerView {
+    pub id: Uuid,
+    pub name: String,
+    pub is_2d: bool,
+    // 200 until the first GamePacket
+    pub scenario: u8,
+    pub kingdom: Option<Kingdom>,
+}
+
+impl PlayerView {
+    pub fn of(client: &Client) -> PlayerView {
+        PlayerView {
+            id: client.id,
+            name: client.name.to_string(),
+            is_2d: client.metadata.is_2d,
+            scenario: client.metadata.scenario,
+            kingdom: client.metadata.kingdom,
+        }
+    }
+}
+
+/// A packet on its way through the hooks, the body is kept serialized so
+/// hooks can work on any packet type
+pub struct HookPacket {
+    pub header: IPacket<PacketHeader>,
+    pub body: Vec<u8>,
+}
+
+impl HookPacket {
+    pub fn new<T: IPacketTrait>(header: &IPacket<PacketHeader>, packet: &T) -> HookPacket {
+        let mut hook_packet = HookPacket {
+            header: header.copy(),
+            body: Vec::new(),
+        };
+        hook_packet.encode(packet);
+        hook_packet
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        self.header.packet.packet_type
+    }
+
+    pub fn decode<T: IPacketTrait>(&self) -> T {
+        let mut packet = T::new();
+        packet.deserialize(&self.body);
+        packet
+    }
+
+    pub fn encode<T: IPacketTrait>(&mut self, packet: &T) {
+        self.body = packet.serialize()[..packet.get_size().to_owned()].to_vec();
+        self.header.packet.packet_size = self.body.len() as i16;
+    }
+
+    pub fn copy(&self) -> HookPacket {
+        HookPacket {
+            header: self.header.copy(),
+            body: self.body.clone(),
+        }
+    }
+
+    /// Header and body, ready for `Client::send_raw_data`
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame: Vec<u8> = Vec::with_capacity(self.header.packet_size + self.body.len());
+        frame.extend_from_slice(&self.header.serialize()[..self.header.packet_size]);
+        frame.extend_from_slice(&self.body);
+        frame
+    }
+}
+
+pub enum HookAction {
+    Continue,
+    Drop,
+    /// Sends these instead, none to drop it or several to fan it out or add packets.
+    /// Later hooks see each of them
+    Replace(Vec<HookPacket>),
+}
+
+/// Intercepts packets between the sender and every receiver.
+/// Hooks run in the order they were registered and must not block, they are called with the server locked.
+pub trait PacketHook: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Called once when a packet arrives, before the server updates any state from it.
+    /// Dropping it here drops it for everyone. Replacements of the same type update the server's state,
+    /// other types are only relayed
+    fn on_receive(&self, _settings: &Settings, _sender: &PlayerView, _packet: &mut HookPacket) -> HookAction {
+        HookAction::Continue
+    }
+
+    /// Called with a copy of the packet for each receiver, changes only affect that receiver
+    fn on_relay(&self, _settings: &Settings, _sender: &PlayerView, _receiver: &PlayerView, _packet: &mut HookPacket) -> HookAction {
+        HookAction::Continue
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HookChain {
+    hooks: Vec<Arc<dyn PacketHook>>,
+}
+
+impl HookChain {
+    /// Rate limiting, scenario merging and view transforms, they do nothing until enabled in the settings
+    pub fn defaults() -> HookChain {
+        let mut chain = HookChain::default();
+        chain.register(Arc::new(RateLimitHook::new()));
+        chain.register(Arc::new(ScenarioHook));
+        chain.register(Arc::new(ViewTransformHook));
+        chain
+    }
+
+    pub fn register(&mut self, hook: Arc<dyn PacketHook>) {
+        debug!(hook = hook.name(), "Registered packet hook");
+        self.hooks.push(hook);
+    }
+
+    /// What's left of the packet once every hook has seen it, empty if it was dropped
+    pub fn receive(&self, settings: &Settings, sender: &PlayerView, packet: HookPacket) -> Vec<HookPacket> {
+        self.run(packet, |hook, packet| hook.on_receive(settings, sender, packet))
+    }
+
+    /// What to send this receiver, empty if the packet was dropped for them
+    pub fn relay(&self, settings: &Settings, sender: &PlayerView, receiver: &PlayerView, packet: HookPacket) -> Vec<HookPacket> {
+        self.run(packet, |hook, packet| hook.on_relay(settings, sender, receiver, packet))
+    }
+
+    fn run(&self, packet: HookPacket, mut call: impl FnMut(&Arc<dyn PacketHook>, &mut HookPacket) -> HookAction) -> Vec<HookPacket> {
+        let mut packets = vec![packet];
+        for hook in self.hooks.iter() {
+            let mut next = Vec::with_capacity(packets.len());
+            for mut packet in packets {
+                match call(hook, &mut packet) {
+                    HookAction::Continue => next.push(packet),
+                    HookAction::Drop => {},
+                    HookAction::Replace(replacements) => next.extend(replacements),
+                }
+            }
+            packets = next;
+            if packets.is_empty() {
+                break;
+            }
+        }
+        packets
+    }
+}
+
+/// Rewrites the scenario in GamePackets so merged players see each other, see `ScenarioTable`
+pub struct ScenarioHook;
+
+impl PacketHook for ScenarioHook {
+    fn name(&self) -> &str {
+        "scenario"
+    }
+
+    fn on_relay(&self, settings: &Settings, sender: &PlayerView, receiver: &PlayerView, packet: &mut HookPacket) -> HookAction {
+        if packet.packet_type() != PacketType::Game || !settings.scenario.is_active() {
+            return HookAction::Continue;
+        }
+
+        if let Some(scenario) = settings.scenario.scenario_for(sender.kingdom, &sender.id, &receiver.id, receiver.scenario) {
+            let mut game_packet = packet.decode::<IPacket<GamePacket>>();
+            game_packet.packet.scenario_num = scenario;
+            packet.encode(&game_packet);
+        }
+        HookAction::Continue
+    }
+}
+
+/// Flips, mirrors and offsets PlayerPackets per viewer, see `ViewTransform`
+pub struct ViewTransformHook;
+
+impl PacketHook for ViewTransformHook {
+    fn name(&self) -> &str {
+        "view transforms"
+    }
+
+    fn on_relay(&self, settings: &Settings, sender: &PlayerView, receiver: &PlayerView, packet: &mut HookPacket) -> HookAction {
+        if packet.packet_type() != PacketType::Player {
+            return HookAction::Continue;
+        }
+
+        let transform = ViewTransform::for_pair(settings, &receiver.id, &sender.id);
+        if !transform.is_identity() {
+            let mut player_packet = packet.decode::<IPacket<PlayerPacket>>();
+            transform.apply(&mut player_packet.packet, sender.is_2d);
+            packet.encode(&player_packet);
+        }
+        HookAction::Continue
+    }
+}
+
+/// Drops PlayerPackets and CapPackets from players sending more than `RateLimitTable::packets_per_second`.
+/// Other packets change state, dropping them would desync players
+pub struct RateLimitHook {
+    windows: Mutex<RateWindows>,
+}
+
+struct RateWindows {
+    // Start of each player's current window and packets seen in it
+    counts: HashMap<Uuid, (Instant, u32)>,
+    // Players who stopped sending are forgotten after a while
+    pruned_at: Instant,
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const RATE_WINDOW_EXPIRY: Duration = Duration::from_secs(30);
+
+impl Default for RateLimitHook {
+    fn default() -> Self {
+        RateLimitHook::new()
+    }
+}
+
+impl RateLimitHook {
+    pub fn new() -> RateLimitHook {
+        RateLimitHook {
+            windows: Mutex::new(RateWindows {
+                counts: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl PacketHook for RateLimitHook {
+    fn name(&self) -> &str {
+        "rate limit"
+    }
+
+    fn on_receive(&self, settings: &Settings, sender: &PlayerView, packet: &mut HookPacket) -> HookAction {
+        if !settings.rate_limit.enabled || !matches!(packet.packet_type(), PacketType::Player | PacketType::Cap) {
+            return HookAction::Continue;
+        }
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if now.duration_since(windows.pruned_at) >= RATE_WINDOW_EXPIRY {
+            windows.counts.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW_EXPIRY);
+            windows.pruned_at = now;
+        }
+
+        let window = windows.counts.entry(sender.id).or_insert((now, 0));
+        if now.duration_since(window.0) >= RATE_WINDOW {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+
+        if window.1 > settings.rate_limit.packets_per_second {
+            HookAction::Drop
+        } else {
+            HookAction::Continue
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Super Mario Odyssey Online server as a library, shared by the server binary, the tools in `src/bin` and the tests.
+//!
+//! - [`packet`] encodes and decodes what the mod sends
+//! - [`server`] accepts connections and relays packets between players, each of them a [`client`]
+//! - [`builder`] starts a server and controls it while it runs
+//! - [`settings`] configures the server
+//! - [`lib::rot`] has the quaternion and matrix helpers used to transform players
+//!
+//! Running a server inside another program:
+//!
+//! ```no_run
+//! use smo_rusty_online::{builder::ServerBuilder, events::ServerEvent, settings::Settings};
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let server = ServerBuilder::new()
+//!     .settings(Settings::defaults())
+//!     .bind("127.0.0.1:0")
+//!     .on_event(|event| if let ServerEvent::PlayerJoined { name, .. } = event {
+//!         println!("{} joined", name);
+//!     })
+//!     .start()
+//!     .await?;
+//! println!("Listening on {}", server.local_addr());
+//!
+//! for player in server.players().await {
+//!     println!("{} is connected", player.name);
+//! }
+//! let _ = server.kick("*").await;
+//! server.shutdown().await
+//! # }
+//! ```
+//!
+//! Decoding a packet body:
+//!
+//! ```
+//! use smo_rusty_online::packet::packets::{IPacket::{IPacket, IPacketTrait}, ShinePacket::ShinePacket};
+//!
+//! let mut shine = IPacket::<ShinePacket>::new();
+//! shine.deserialize(&[0x39, 0x05, 0x00, 0x00]);
+//! assert_eq!(shine.packet.shine_id, 1337);
+//! ```
+
+pub mod packet;
+pub(crate) mod constants;
+pub mod stages;
+pub mod settings;
+pub mod reload;
+pub mod capture;
+pub mod server;
+pub mod builder;
+pub mod console;
+pub mod client;
+pub(crate) mod transform;
+pub(crate) mod smoothing;
+pub(crate) mod tick;
+pub mod registry;
+pub mod events;
+pub mod hooks;
+pub mod shines;
+pub mod persist;
+pub mod metrics;
+pub mod logging;
+// Only for the tests, the fuzz targets and smo-load, not part of the server
+#[cfg(any(test, feature = "testing"))]
+pub mod bot;
+#[cfg(any(test, feature = "testing"))]
+pub mod fuzz;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(feature = "admin-api")]
+pub(crate) mod admin;
+// src/lib.rs is the crate root, so the math helpers need an explicit path
+#[path = "lib/mod.rs"]
+pub mod lib;
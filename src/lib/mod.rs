@@ -1 +1,3 @@
+//! Math helpers, not all of them are used by the server itself
+#[allow(dead_code)]
 pub mod rot;
\ No newline at end of file
@@ -1,5 +1,9 @@
+//! Rotation helpers for nalgebra's quaternions and matrices, which this version of nalgebra doesn't have
 use std::f32::consts::PI;
-use nalgebra::{Quaternion, Matrix4};
+use nalgebra::{Quaternion, Matrix4, Vector3};
+
+// Below this the quaternions are close enough that slerp falls back to a normalised lerp
+const SLERP_LERP_THRESHOLD: f32 = 0.9995;
 
 pub trait QuaternionMatrixConvertible {
     fn create_from_rotation_matrix(matrix: Matrix4<f32>) -> Self;
@@ -7,40 +11,145 @@ pub trait QuaternionMatrixConvertible {
     fn create_from_rotation_matrix_y() -> Self;
 }
 impl QuaternionMatrixConvertible for Quaternion<f32> {
+    /// Shepperd's method, branches on the largest of w, i, j, k so 180° rotations stay finite
     fn create_from_rotation_matrix(matrix: Matrix4<f32>) -> Self {
-        // println!("create_from_rotation_matrix");
-        let w: f32 = (1.0 + matrix.m11 + matrix.m22 + matrix.m33).sqrt() / 2.0;
-        let w4: f32 = 4.0 * w;
-        let i: f32 = (matrix.m32 - matrix.m23) / w4;
-        let j: f32 = (matrix.m13 - matrix.m31) / w4;
-        let k: f32 = (matrix.m21 - matrix.m12) / w4;
-        Quaternion::<f32> {
-            w,
-            i,
-            j,
-            k
-        }
+        let trace: f32 = matrix.m11 + matrix.m22 + matrix.m33;
+
+        let quaternion = if trace >= matrix.m11 && trace >= matrix.m22 && trace >= matrix.m33 {
+            let s: f32 = (1.0 + trace).sqrt() * 2.0;
+            Quaternion::<f32> {
+                w: s / 4.0,
+                i: (matrix.m32 - matrix.m23) / s,
+                j: (matrix.m13 - matrix.m31) / s,
+                k: (matrix.m21 - matrix.m12) / s,
+            }
+        } else if matrix.m11 >= matrix.m22 && matrix.m11 >= matrix.m33 {
+            let s: f32 = (1.0 + matrix.m11 - matrix.m22 - matrix.m33).sqrt() * 2.0;
+            Quaternion::<f32> {
+                w: (matrix.m32 - matrix.m23) / s,
+                i: s / 4.0,
+                j: (matrix.m12 + matrix.m21) / s,
+                k: (matrix.m13 + matrix.m31) / s,
+            }
+        } else if matrix.m22 >= matrix.m33 {
+            let s: f32 = (1.0 + matrix.m22 - matrix.m11 - matrix.m33).sqrt() * 2.0;
+            Quaternion::<f32> {
+                w: (matrix.m13 - matrix.m31) / s,
+                i: (matrix.m12 + matrix.m21) / s,
+                j: s / 4.0,
+                k: (matrix.m23 + matrix.m32) / s,
+            }
+        } else {
+            let s: f32 = (1.0 + matrix.m33 - matrix.m11 - matrix.m22).sqrt() * 2.0;
+            Quaternion::<f32> {
+                w: (matrix.m21 - matrix.m12) / s,
+                i: (matrix.m13 + matrix.m31) / s,
+                j: (matrix.m23 + matrix.m32) / s,
+                k: s / 4.0,
+            }
+        };
+
+        quaternion.normalized()
     }
 
     fn create_from_rotation_matrix_x() -> Self {
-        // println!("create_from_rotation_matrix_x");
         let matrix = Matrix4::<f32>::create_rotation_x(PI);
-        return Quaternion::<f32>::create_from_rotation_matrix(matrix);
+        Quaternion::<f32>::create_from_rotation_matrix(matrix)
     }
 
     fn create_from_rotation_matrix_y() -> Self {
-        // println!("create_from_rotation_matrix_y");
         let matrix = Matrix4::<f32>::create_rotation_y(PI);
-        return Quaternion::<f32>::create_from_rotation_matrix(matrix);
+        Quaternion::<f32>::create_from_rotation_matrix(matrix)
+    }
+}
+
+pub trait QuaternionRotation {
+    fn identity() -> Self;
+    fn from_axis_angle(axis: Vector3<f32>, radians: f32) -> Self;
+    /// Same convention as nalgebra: roll around X, then pitch around Y, then yaw around Z
+    fn from_euler_angles(roll: f32, pitch: f32, yaw: f32) -> Self;
+    /// Zero length quaternions normalise to the identity
+    fn normalized(&self) -> Self;
+    fn dot(&self, other: &Self) -> f32;
+    /// Takes the shortest path, `t` is clamped to 0..=1
+    fn slerp(&self, other: &Self, t: f32) -> Self;
+}
+impl QuaternionRotation for Quaternion<f32> {
+    fn identity() -> Self {
+        Quaternion::new(1.0, 0.0, 0.0, 0.0)
+    }
+
+    fn from_axis_angle(axis: Vector3<f32>, radians: f32) -> Self {
+        let length = (axis.x * axis.x + axis.y * axis.y + axis.z * axis.z).sqrt();
+        if length == 0.0 || !length.is_finite() {
+            return Quaternion::identity();
+        }
+
+        let (sin, cos) = (radians / 2.0).sin_cos();
+        let scale = sin / length;
+        Quaternion::new(cos, axis.x * scale, axis.y * scale, axis.z * scale)
+    }
+
+    fn from_euler_angles(roll: f32, pitch: f32, yaw: f32) -> Self {
+        let (sr, cr) = (roll / 2.0).sin_cos();
+        let (sp, cp) = (pitch / 2.0).sin_cos();
+        let (sy, cy) = (yaw / 2.0).sin_cos();
+
+        Quaternion::new(
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy
+        )
+    }
+
+    fn normalized(&self) -> Self {
+        let length = self.dot(self).sqrt();
+        if length == 0.0 || !length.is_finite() {
+            return Quaternion::identity();
+        }
+        Quaternion::new(self.w / length, self.i / length, self.j / length, self.k / length)
+    }
+
+    fn dot(&self, other: &Self) -> f32 {
+        self.w * other.w + self.i * other.i + self.j * other.j + self.k * other.k
+    }
+
+    fn slerp(&self, other: &Self, t: f32) -> Self {
+        let t = t.clamp(0.0, 1.0);
+        let from = self.normalized();
+        let mut to = other.normalized();
+
+        // q and -q are the same rotation, go the short way around
+        let mut cos_theta = from.dot(&to);
+        if cos_theta < 0.0 {
+            to = Quaternion::new(-to.w, -to.i, -to.j, -to.k);
+            cos_theta = -cos_theta;
+        }
+
+        let (from_weight, to_weight) = if cos_theta > SLERP_LERP_THRESHOLD {
+            (1.0 - t, t)
+        } else {
+            let theta = cos_theta.acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+
+        Quaternion::new(
+            from.w * from_weight + to.w * to_weight,
+            from.i * from_weight + to.i * to_weight,
+            from.j * from_weight + to.j * to_weight,
+            from.k * from_weight + to.k * to_weight
+        ).normalized()
     }
 }
+
 pub trait MatrixConvertible {
     fn create_rotation_x(radians: f32) -> Self;
     fn create_rotation_y(radians: f32) -> Self;
 }
 impl MatrixConvertible for Matrix4<f32> {
     fn create_rotation_x(radians: f32) -> Self {
-        // println!("create_rotation_x");
         Matrix4::<f32>::new(
             1.0, 0.0, 0.0, 0.0,
             0.0, radians.cos(), -radians.sin(), 0.0,
@@ -49,7 +158,6 @@ impl MatrixConvertible for Matrix4<f32> {
         )
     }
     fn create_rotation_y(radians: f32) -> Self {
-        // println!("create_rotation_y");
         Matrix4::<f32>::new(
             radians.cos(), 0.0, radians.sin(), 0.0,
             0.0, 1.0, 0.0, 0.0,
@@ -57,4 +165,99 @@ impl MatrixConvertible for Matrix4<f32> {
             0.0, 0.0, 0.0, 1.0
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use nalgebra::{Quaternion, Matrix4, Vector3, UnitQuaternion, ToHomogeneous};
+    use proptest::prelude::*;
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn same_rotation(a: &Quaternion<f32>, b: &Quaternion<f32>) -> bool {
+        (a.dot(b).abs() - 1.0).abs() < EPSILON
+    }
+
+    fn matrix_of(q: &UnitQuaternion<f32>) -> Matrix4<f32> {
+        q.to_rotation_matrix().to_homogeneous()
+    }
+
+    fn axis() -> impl Strategy<Value = Vector3<f32>> {
+        (-1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0)
+            .prop_filter("axis needs a direction", |(x, y, z)| x * x + y * y + z * z > 0.01)
+            .prop_map(|(x, y, z)| Vector3::new(x, y, z))
+    }
+
+    fn unit(v: Vector3<f32>) -> Vector3<f32> {
+        let length = (v.x * v.x + v.y * v.y + v.z * v.z).sqrt();
+        Vector3::new(v.x / length, v.y / length, v.z / length)
+    }
+
+    #[test]
+    fn half_turns_have_no_nans() {
+        for axis in [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0), unit(Vector3::new(1.0, 1.0, 0.0))] {
+            let expected = UnitQuaternion::new(axis * PI);
+            let actual = Quaternion::<f32>::create_from_rotation_matrix(matrix_of(&expected));
+            assert!(same_rotation(&actual, expected.quaternion()), "{:?} != {:?}", actual, expected.quaternion());
+        }
+    }
+
+    #[test]
+    fn flip_is_a_half_turn_around_z() {
+        let flip = Quaternion::<f32>::create_from_rotation_matrix_x() * Quaternion::<f32>::create_from_rotation_matrix_y();
+        assert!(same_rotation(&flip, &Quaternion::new(0.0, 0.0, 0.0, 1.0)), "{:?}", flip);
+    }
+
+    #[test]
+    fn normalizing_zero_gives_identity() {
+        assert_eq!(Quaternion::new(0.0, 0.0, 0.0, 0.0).normalized(), Quaternion::identity());
+    }
+
+    proptest! {
+        #[test]
+        fn matrix_conversion_matches_nalgebra(axis in axis(), angle in -2.0 * PI..2.0 * PI) {
+            let expected = UnitQuaternion::new(unit(axis) * angle);
+            let actual = Quaternion::<f32>::create_from_rotation_matrix(matrix_of(&expected));
+            prop_assert!(same_rotation(&actual, expected.quaternion()), "{:?} != {:?}", actual, expected.quaternion());
+        }
+
+        #[test]
+        fn axis_angle_matches_nalgebra(axis in axis(), angle in -2.0 * PI..2.0 * PI) {
+            let expected = UnitQuaternion::new(unit(axis) * angle);
+            let actual = Quaternion::<f32>::from_axis_angle(axis, angle);
+            prop_assert!(same_rotation(&actual, expected.quaternion()), "{:?} != {:?}", actual, expected.quaternion());
+        }
+
+        #[test]
+        fn euler_angles_match_nalgebra(roll in -PI..PI, pitch in -PI..PI, yaw in -PI..PI) {
+            let expected = UnitQuaternion::new_with_euler_angles(roll, pitch, yaw);
+            let actual = Quaternion::<f32>::from_euler_angles(roll, pitch, yaw);
+            prop_assert!(same_rotation(&actual, expected.quaternion()), "{:?} != {:?}", actual, expected.quaternion());
+        }
+
+        #[test]
+        fn normalized_has_unit_length(w in -100.0f32..100.0, i in -100.0f32..100.0, j in -100.0f32..100.0, k in -100.0f32..100.0) {
+            let q = Quaternion::new(w, i, j, k).normalized();
+            prop_assert!((q.dot(&q) - 1.0).abs() < EPSILON);
+        }
+
+        #[test]
+        fn slerp_follows_the_rotation_arc(axis in axis(), angle in -3.0f32..3.0, t in 0.0f32..1.0) {
+            let from = Quaternion::<f32>::identity();
+            let to = Quaternion::<f32>::from_axis_angle(axis, angle);
+            let expected = UnitQuaternion::new(unit(axis) * (angle * t));
+            let actual = from.slerp(&to, t);
+            prop_assert!(same_rotation(&actual, expected.quaternion()), "{:?} != {:?}", actual, expected.quaternion());
+        }
+
+        #[test]
+        fn slerp_hits_both_ends(axis in axis(), angle in -2.0 * PI..2.0 * PI) {
+            let from = Quaternion::<f32>::from_axis_angle(axis, angle / 3.0);
+            let to = Quaternion::<f32>::from_axis_angle(axis, angle);
+            prop_assert!(same_rotation(&from.slerp(&to, 0.0), &from));
+            prop_assert!(same_rotation(&from.slerp(&to, 1.0), &to));
+        }
+    }
+}
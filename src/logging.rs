@@ -0,0 +1,20 @@
+use tracing_subscriber::{
+    EnvFilter,
+    fmt
+};
+use crate::settings::LoggingTable;
+
+/// Installs the global subscriber, call once before anything logs.
+/// `SMO_LOG` is applied to `filter` by `SettingsOverrides`, e.g. `SMO_LOG=debug,smo_rusty_online::tick=trace`
+pub fn init(settings: &LoggingTable) {
+    let filter = EnvFilter::new(&settings.filter);
+
+    let builder = fmt()
+        .with_env_filter(filter)
+        .with_target(true);
+    if settings.json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
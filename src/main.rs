@@ -1,41 +1,176 @@
-mod packet;
-mod server;
-mod client;
-mod constants;
-mod settings;
-mod lib;
-use mempool::Pool;
-use packet::packets::{IPacket, CapPacket::CapPacket};
-use server::{Server, ServerWrapper};
-use settings::{Settings};
+use smo_rusty_online::{
+    builder::ServerBuilder,
+    capture,
+    console::Console,
+    logging,
+    reload::SettingsReloader,
+    server::{Server, ServerWrapper},
+    settings::{Settings, SettingsOverrides, SETTINGS_FILE, parse_override}
+};
 use tokio::{
-    net::TcpListener,
-    sync::{Mutex, RwLock}
+    signal,
+    sync::RwLock
 };
+use tracing::{info, warn};
 use std::{
-    io::Result, sync::{
-        Arc, 
-        // Mutex
-    }
+    env,
+    io::Result,
+    process,
+    sync::Arc
 };
 
+const USAGE: &str = "\
+Usage: smo-rusty-online [options]
+
+Options and SMO_* environment variables override the settings file, options win over environment variables.
+
+Options:
+  -c, --config <file>         Settings file, created with the defaults if it's missing [env: SMO_CONFIG] [default: ./settings.json]
+  -a, --address <ip>          Address to listen on, server.address [env: SMO_ADDRESS]
+  -p, --port <port>           Port to listen on, server.port [env: SMO_PORT]
+  -m, --max-players <count>   Players allowed at once, server.max_players [env: SMO_MAX_PLAYERS]
+  -l, --log <filter>          Log filter, logging.filter [env: SMO_LOG]
+      --shines-file <file>    Save collected shines to this file, enables persist_shines [env: SMO_SHINES_FILE]
+      --admin-port <port>     Port for the admin API, admin.address [env: SMO_ADMIN_PORT]
+      --replay <capture>      Run a packet capture through the server instead of listening
+      --print-default-config  Print the default settings file and exit
+      --check-config          Check the settings file with the overrides applied and exit
+  -h, --help                  Show this message";
+
+#[derive(Default)]
+struct Options {
+    config: Option<String>,
+    overrides: SettingsOverrides,
+    replay: Option<String>,
+    print_default_config: bool,
+    check_config: bool,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let settings = Settings::defaults();
-    let addr: &str = "0.0.0.0:1027";
-    let listener: TcpListener = TcpListener::bind(addr).await.unwrap();
-
-    let server: Arc<RwLock<Server>> = Arc::new(
-        RwLock::new(
-            Server {
-                clients: vec![],
-                mempool: Pool::new(Box::new(|| [0; 1024])),
-                settings: settings,
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    let overrides = match SettingsOverrides::from_env() {
+        Ok(overrides) => overrides.merge(options.overrides),
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+    let config = options.config
+        .or_else(|| env::var("SMO_CONFIG").ok().filter(|config| !config.is_empty()))
+        .unwrap_or(SETTINGS_FILE.to_string());
+
+    if options.print_default_config {
+        println!("{}", serde_json::to_string_pretty(&Settings::defaults())?);
+        return Ok(());
+    }
+    if options.check_config {
+        match Settings::read(&config, &overrides) {
+            Ok(settings) => println!("{} is valid, listening on {}", config, settings.server.bind_address()?),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
             }
-        )
-    );
-    
-    ServerWrapper::start(server, listener).await;
+        }
+        return Ok(());
+    }
+
+    let settings = Settings::load(&config, &overrides)?;
+    logging::init(&settings.logging);
+
+    // Loaded before capturing starts so capturing the replay can't overwrite it
+    let replay = match &options.replay {
+        Some(file_name) => Some(capture::load(file_name)?),
+        None => None,
+    };
+    capture::start(&settings.capture)?;
+
+    if let Some(records) = replay {
+        let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server::new(settings)));
+        ServerWrapper::spawn_tasks(server.clone()).await;
+        ServerWrapper::replay(server, records).await;
+        return Ok(());
+    }
+
+    let mut server = ServerBuilder::new()
+        .loaded_settings_file(&config, settings)
+        .overrides(overrides)
+        .start()
+        .await?;
+    info!(address = %server.local_addr(), "Listening");
+
+    tokio::spawn(Console::start(server.server()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(server.server()));
+
+    tokio::select! {
+        result = server.wait() => return result,
+        _ = shutdown_signal() => {},
+    }
+    // A second signal skips waiting for players and saving
+    tokio::select! {
+        result = server.shutdown() => result,
+        _ = shutdown_signal() => {
+            warn!("Exiting without finishing the shutdown");
+            Ok(())
+        },
+    }
+}
 
-    Ok(())
+fn parse_args(args: Vec<String>) -> std::result::Result<Options, String> {
+    let mut options = Options::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "-c" | "--config" => options.config = Some(value("--config")?),
+            "-a" | "--address" => options.overrides.address = Some(value("--address")?),
+            "-p" | "--port" => options.overrides.port = Some(parse_override("--port", &value("--port")?)?),
+            "-m" | "--max-players" => options.overrides.max_players = Some(parse_override("--max-players", &value("--max-players")?)?),
+            "-l" | "--log" => options.overrides.log = Some(value("--log")?),
+            "--shines-file" => options.overrides.shines_file = Some(value("--shines-file")?),
+            "--admin-port" => options.overrides.admin_port = Some(parse_override("--admin-port", &value("--admin-port")?)?),
+            "--replay" => options.replay = Some(value("--replay")?),
+            "--print-default-config" => options.print_default_config = true,
+            "--check-config" => options.check_config = true,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(server: Arc<RwLock<Server>>) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        match SettingsReloader::reload(server.clone()).await {
+            Ok(message) => info!("{}", message),
+            Err(message) => warn!("{}", message),
+        }
+    }
+}
+
+// Ctrl+C, or SIGTERM from `docker stop` and service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
 }
\ No newline at end of file
@@ -0,0 +1,307 @@
+use std::{
+    fmt::Write,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering}
+    },
+    time::Duration
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{RwLock, Semaphore},
+    time
+};
+use tracing::{error, info, warn};
+use crate::{
+    client::SEND_QUEUE_SIZE,
+    packet::{
+        PacketHeader::SIZE as PACKET_HEADER_SIZE,
+        PacketType::PacketType
+    },
+    registry::DisconnectReason,
+    server::Server
+};
+
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+// A scraper that connects and sends nothing is dropped after this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// Connections served at once, accepting waits for one to finish
+const MAX_CONNECTIONS: usize = 16;
+
+// Indexed by `PacketType as usize`
+const PACKET_TYPES: [&str; 13] = [
+    "Unknown", "Init", "Player", "Cap", "Game", "Tag", "Connect",
+    "Disconnect", "Costume", "Shine", "Capture", "ChangeStage", "Command",
+];
+
+const DISCONNECT_REASONS: [DisconnectReason; 6] = [
+    DisconnectReason::Closed,
+    DisconnectReason::ReadError,
+    DisconnectReason::SendFailed,
+    DisconnectReason::Kicked,
+    DisconnectReason::Replaced,
+    DisconnectReason::Shutdown,
+];
+
+// Upper bounds in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DecodeError {
+    // The connection ended part way through a packet header
+    ShortHeader,
+    // Negative or larger than a frame can be
+    BadSize,
+    UnknownType,
+    // The connection ended part way through a packet body
+    Truncated,
+}
+
+impl DecodeError {
+    const ALL: [DecodeError; 4] = [DecodeError::ShortHeader, DecodeError::BadSize, DecodeError::UnknownType, DecodeError::Truncated];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DecodeError::ShortHeader => "short_header",
+            DecodeError::BadSize => "bad_size",
+            DecodeError::UnknownType => "unknown_type",
+            DecodeError::Truncated => "truncated",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Process wide counters, read by the metrics endpoint
+pub struct Metrics {
+    packets_in: [AtomicU64; PACKET_TYPES.len()],
+    bytes_in: [AtomicU64; PACKET_TYPES.len()],
+    packets_out: [AtomicU64; PACKET_TYPES.len()],
+    bytes_out: [AtomicU64; PACKET_TYPES.len()],
+    decode_errors: [AtomicU64; DecodeError::ALL.len()],
+    disconnects: [AtomicU64; DISCONNECT_REASONS.len()],
+    broadcast_latency: Histogram,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            packets_in: [const { AtomicU64::new(0) }; PACKET_TYPES.len()],
+            bytes_in: [const { AtomicU64::new(0) }; PACKET_TYPES.len()],
+            packets_out: [const { AtomicU64::new(0) }; PACKET_TYPES.len()],
+            bytes_out: [const { AtomicU64::new(0) }; PACKET_TYPES.len()],
+            decode_errors: [const { AtomicU64::new(0) }; DecodeError::ALL.len()],
+            disconnects: [const { AtomicU64::new(0) }; DISCONNECT_REASONS.len()],
+            broadcast_latency: Histogram::new(),
+        }
+    }
+
+    pub fn packet_in(&self, packet_type: PacketType, bytes: usize) {
+        self.packets_in[packet_type as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_in[packet_type as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts every packet in a frame written to a socket, frames can hold a batch of packets
+    pub fn frame_out(&self, frame: &[u8]) {
+        let mut offset = 0;
+        while offset + PACKET_HEADER_SIZE <= frame.len() {
+            let packet_type = u16::from_le_bytes([frame[offset + 16], frame[offset + 17]]);
+            let packet_size = i16::from_le_bytes([frame[offset + 18], frame[offset + 19]]).max(0) as usize;
+            let index = if (packet_type as usize) < PACKET_TYPES.len() { packet_type as usize } else { PacketType::Unknown as usize };
+            let bytes = (PACKET_HEADER_SIZE + packet_size).min(frame.len() - offset);
+
+            self.packets_out[index].fetch_add(1, Ordering::Relaxed);
+            self.bytes_out[index].fetch_add(bytes as u64, Ordering::Relaxed);
+            offset += bytes;
+        }
+    }
+
+    pub fn decode_error(&self, error: DecodeError) {
+        self.decode_errors[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnect(&self, reason: DisconnectReason) {
+        self.disconnects[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time taken to queue one packet for every receiver
+    pub fn broadcast(&self, elapsed: Duration) {
+        self.broadcast_latency.observe(elapsed);
+    }
+
+    /// Prometheus text exposition format
+    pub async fn render(&self, server: Arc<RwLock<Server>>) -> String {
+        let (players, shines) = {
+            let s = server.read().await;
+            (s.players.clone(), s.shines.all().len())
+        };
+        let known = players.snapshot();
+        let connected: Vec<_> = known.iter().filter(|player| player.is_connected()).collect();
+
+        let mut out = String::new();
+        gauge(&mut out, "smo_players_connected", "Players currently connected", connected.len() as u64);
+        gauge(&mut out, "smo_players_known", "Players that connected since the server started", known.len() as u64);
+        gauge(&mut out, "smo_shine_bag_size", "Shines collected by anyone", shines as u64);
+
+        header(&mut out, "smo_send_queue_depth", "gauge", "Frames waiting to be written to a player's socket");
+        for player in connected.iter() {
+            let depth = SEND_QUEUE_SIZE - player.outgoing.capacity();
+            let _ = writeln!(out, "smo_send_queue_depth{{player=\"{}\",id=\"{}\"}} {}", escape(&player.name), player.id, depth);
+        }
+
+        per_type(&mut out, "smo_packets_received_total", "Packets received by type", &self.packets_in);
+        per_type(&mut out, "smo_bytes_received_total", "Bytes received by packet type, including headers", &self.bytes_in);
+        per_type(&mut out, "smo_packets_sent_total", "Packets written to sockets by type", &self.packets_out);
+        per_type(&mut out, "smo_bytes_sent_total", "Bytes written to sockets by packet type, including headers", &self.bytes_out);
+
+        header(&mut out, "smo_decode_errors_total", "counter", "Inbound bytes that could not be decoded as a packet");
+        for error in DecodeError::ALL {
+            let _ = writeln!(out, "smo_decode_errors_total{{kind=\"{}\"}} {}", error.as_str(), self.decode_errors[error as usize].load(Ordering::Relaxed));
+        }
+
+        header(&mut out, "smo_disconnects_total", "counter", "Players that left, by reason");
+        for reason in DISCONNECT_REASONS {
+            let _ = writeln!(out, "smo_disconnects_total{{reason=\"{}\"}} {}", reason.as_str(), self.disconnects[reason as usize].load(Ordering::Relaxed));
+        }
+
+        let latency = &self.broadcast_latency;
+        header(&mut out, "smo_broadcast_seconds", "histogram", "Time taken to queue a packet for every receiver");
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(latency.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "smo_broadcast_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let count = latency.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "smo_broadcast_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(out, "smo_broadcast_seconds_sum {}", latency.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9);
+        let _ = writeln!(out, "smo_broadcast_seconds_count {}", count);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn per_type(out: &mut String, name: &str, help: &str, values: &[AtomicU64]) {
+    header(out, name, "counter", help);
+    for (packet_type, value) in PACKET_TYPES.iter().zip(values.iter()) {
+        let _ = writeln!(out, "{}{{type=\"{}\"}} {}", name, packet_type, value.load(Ordering::Relaxed));
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` for Prometheus on `MetricsTable::address`
+pub struct MetricsServer;
+
+impl MetricsServer {
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let (enabled, address) = {
+            let metrics = &server.read().await.settings.metrics;
+            (metrics.enabled, metrics.address.to_string())
+        };
+        if !enabled {
+            return;
+        }
+
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(%address, %error, "Metrics failed to bind");
+                return;
+            }
+        };
+        info!(%address, "Metrics listening");
+
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            let permit = connections.clone().acquire_owned().await.unwrap();
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                // Errors like running out of file descriptors last a while, don't spin on them
+                Err(error) => {
+                    warn!(%error, "Metrics failed to accept a connection");
+                    time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                },
+            };
+            let server = server.clone();
+            tokio::spawn(async move {
+                MetricsServer::respond(server, socket).await;
+                drop(permit);
+            });
+        }
+    }
+
+    async fn respond(server: Arc<RwLock<Server>>, mut socket: TcpStream) {
+        let request = match time::timeout(REQUEST_TIMEOUT, MetricsServer::read_request(&mut socket)).await {
+            Ok(Some(request)) => request,
+            _ => return,
+        };
+
+        let response = if request.starts_with(b"GET /metrics ") {
+            let body = METRICS.render(server).await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+        let _ = socket.write_all(response.as_bytes()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    /// Reads until the blank line ending the headers, which can arrive over several reads.
+    /// `None` when the connection ends first or the headers are too large
+    async fn read_request(socket: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+            let size = socket.read(&mut buffer).await.ok()?;
+            if size == 0 || request.len() + size > MAX_REQUEST_SIZE {
+                return None;
+            }
+            request.extend_from_slice(&buffer[..size]);
+        }
+        Some(request)
+    }
+}
@@ -46,6 +46,7 @@ impl IPacketTrait for IPacket<PacketHeader> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
 
         let mut id: [u8; 16] = [0; 16];
         id.copy_from_slice(&data[..16]);
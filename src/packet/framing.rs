@@ -0,0 +1,73 @@
+use crate::{
+    metrics::DecodeError,
+    packet::{
+        PacketHeader::{PacketHeader, SIZE as PACKET_HEADER_SIZE},
+        packets::IPacket::{IPacket, IPacketTrait}
+    }
+};
+
+// Header and body, packets are encoded into 1024 byte buffers
+pub const MAX_FRAME_SIZE: usize = 1024;
+
+/// One packet exactly as it was sent
+pub struct Frame {
+    pub header: IPacket<PacketHeader>,
+    // Header then body
+    pub bytes: Vec<u8>,
+}
+
+impl Frame {
+    pub fn body(&self) -> &[u8] {
+        &self.bytes[PACKET_HEADER_SIZE..]
+    }
+}
+
+/// Splits the bytes read from a socket into packets, a read can end part way through a packet or hold several
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next whole packet, `None` until more bytes are pushed.
+    /// A bad size means the stream can't be split any further, so everything buffered is dropped
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, DecodeError> {
+        if self.buffer.len() < PACKET_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = IPacket::<PacketHeader>::new();
+        header.deserialize(&self.buffer[..PACKET_HEADER_SIZE]);
+        let packet_size = header.packet.packet_size;
+        if packet_size < 0 || PACKET_HEADER_SIZE + packet_size as usize > MAX_FRAME_SIZE {
+            self.buffer.clear();
+            return Err(DecodeError::BadSize);
+        }
+
+        let frame_size = PACKET_HEADER_SIZE + packet_size as usize;
+        if self.buffer.len() < frame_size {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            header,
+            bytes: self.buffer.drain(..frame_size).collect(),
+        }))
+    }
+
+    /// What's wrong with the bytes left over when the connection ends, if any
+    pub fn leftover(&self) -> Option<DecodeError> {
+        match self.buffer.len() {
+            0 => None,
+            size if size < PACKET_HEADER_SIZE => Some(DecodeError::ShortHeader),
+            _ => Some(DecodeError::Truncated),
+        }
+    }
+}
@@ -1,3 +1,8 @@
+//! Packets as the mod sends them, a [`PacketHeader::PacketHeader`] followed by a fixed size body.
+//! Each body type is in [`packets`], [`framing`] splits a byte stream back into packets
+// Module names follow the packet types of the C# server
+#![allow(non_snake_case)]
 pub mod packets;
 pub mod PacketType;
-pub mod PacketHeader;
\ No newline at end of file
+pub mod PacketHeader;
+pub mod framing;
\ No newline at end of file
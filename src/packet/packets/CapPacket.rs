@@ -46,6 +46,7 @@ impl IPacketTrait for IPacket<CapPacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.position = self.bytes_to_vec3(&data[..12]);
         self.packet.rotation = self.bytes_to_quad(&data[12..28]);
         
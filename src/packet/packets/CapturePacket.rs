@@ -30,6 +30,7 @@ impl IPacketTrait for IPacket<CapturePacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.module_name = self.bytes_to_string(&data[..SIZE]);
     }
 }
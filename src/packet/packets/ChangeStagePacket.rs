@@ -1,3 +1,4 @@
+use tracing::trace;
 use crate::packet::packets::IPacket::{
     IPacketTrait,
     IPacket
@@ -40,16 +41,17 @@ impl IPacketTrait for IPacket<ChangeStagePacket> {
         returning_data[(ID_SIZE + STAGE_SIZE)..(ID_SIZE + STAGE_SIZE + 1)].copy_from_slice(&self.packet.scenario.to_le_bytes());
         returning_data[(ID_SIZE + STAGE_SIZE + 1)..(ID_SIZE + STAGE_SIZE + 2)].copy_from_slice(&self.packet.sub_scenario_type.to_le_bytes());
 
-        println!("SERIALIZE CHANGE STAGE: {:?}", self.packet.id.to_string());
+        trace!(id = %self.packet.id, "Serialized ChangeStagePacket");
 
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.stage = self.bytes_to_string(&data[..STAGE_SIZE]);
         self.packet.id = self.bytes_to_string(&data[STAGE_SIZE..(ID_SIZE + STAGE_SIZE)]);
         self.packet.scenario = i8::from_le_bytes([data[(ID_SIZE + STAGE_SIZE)]; 1]);
         self.packet.sub_scenario_type = data[(ID_SIZE + STAGE_SIZE + 1)];
 
-        println!("DESERIALIZE CHANGE STAGE: {:?}", self.packet.stage);
+        trace!(stage = %self.packet.stage, "Deserialized ChangeStagePacket");
     }
 }
@@ -3,8 +3,9 @@ use crate::packet::packets::IPacket::{
     IPacket
 };
 
-#[derive(PartialEq)]
-#[repr(u16)]
+// Sent as a u32
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
 pub enum ConnectionTypes {
     FirstConnection = 0x0,
     Reconnecting = 0x1,
@@ -14,10 +15,22 @@ pub struct ConnectPacket {
     pub connection_type: ConnectionTypes,
     pub max_players: u16,
     pub client_name: String,
+    // Sent after the name by mods set up with a server password, see `set_password`
+    pub password: String,
 }
 
 const SIZE: usize = 0x26;
 const NAME_SIZE: usize = 0x20;
+const PASSWORD_SIZE: usize = 0x20;
+
+impl IPacket<ConnectPacket> {
+    /// Appends the password to the packet. A received packet keeps the plain size,
+    /// so the password is never relayed to other players
+    pub fn set_password(&mut self, password: &str) {
+        self.packet.password = password.to_string();
+        self.packet_size = SIZE + PASSWORD_SIZE;
+    }
+}
 impl IPacketTrait for IPacket<ConnectPacket> {
     fn new() -> Self {
         IPacket {
@@ -27,6 +40,7 @@ impl IPacketTrait for IPacket<ConnectPacket> {
                 connection_type: ConnectionTypes::FirstConnection,
                 max_players: 0,
                 client_name: "?????".to_string(),
+                password: String::new(),
             }
         }
     }
@@ -39,23 +53,24 @@ impl IPacketTrait for IPacket<ConnectPacket> {
     fn serialize(&self) -> [u8; 1024] {
         let mut returning_data: [u8; 1024] = [0x0; 1024];
         
-        match self.packet.connection_type {
-            ConnectionTypes::FirstConnection => returning_data[..4].copy_from_slice(&(0 as u32).to_le_bytes()),
-            ConnectionTypes::Reconnecting => returning_data[..4].copy_from_slice(&(1 as u32).to_le_bytes()),
-        }
+        returning_data[..4].copy_from_slice(&(self.packet.connection_type as u32).to_le_bytes());
 
         returning_data[4..6].copy_from_slice(&self.packet.max_players.to_le_bytes());
 
         returning_data[6..SIZE].copy_from_slice(&self.string_to_bytes::<NAME_SIZE>(self.packet.client_name.to_string()));
 
+        returning_data[SIZE..SIZE + PASSWORD_SIZE].copy_from_slice(&self.string_to_bytes::<PASSWORD_SIZE>(self.packet.password.to_string()));
+
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        self.packet.password = self.bytes_to_string(&self.padded::<PASSWORD_SIZE>(data.get(SIZE..).unwrap_or_default()));
+        let data = &self.padded::<SIZE>(data);
         let mut connection_type_bytes: [u8; 4] = [0; 4];
         connection_type_bytes.copy_from_slice(&data[..4]);
-        if u32::from_le_bytes(connection_type_bytes) == 0 {
+        if u32::from_le_bytes(connection_type_bytes) == ConnectionTypes::FirstConnection as u32 {
             self.packet.connection_type = ConnectionTypes::FirstConnection;
-        } else if u32::from_le_bytes(connection_type_bytes) == 1 {
+        } else if u32::from_le_bytes(connection_type_bytes) == ConnectionTypes::Reconnecting as u32 {
             self.packet.connection_type = ConnectionTypes::Reconnecting;
         }
 
@@ -36,6 +36,7 @@ impl IPacketTrait for IPacket<CostumePacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.body_name = self.bytes_to_string(&data[..COSTUME_SIZE]);
         self.packet.cap_name = self.bytes_to_string(&data[COSTUME_SIZE..SIZE]);
     }
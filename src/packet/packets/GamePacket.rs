@@ -38,6 +38,7 @@ impl IPacketTrait for IPacket<GamePacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.is_2d = self.byte_to_bool(data[0]);
         self.packet.scenario_num = data[1];
         self.packet.stage = self.bytes_to_string(&data[2..SIZE]);
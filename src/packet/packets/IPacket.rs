@@ -18,31 +18,34 @@ pub trait IPacketTrait {
     fn bool_to_byte(&self, data: bool) -> u8 {
         if data { 0x1 } else { 0x0 }
     }
+    // Null terminated like the game reads them, strings longer than the field are cut off on a character boundary
     fn string_to_bytes<const SIZE: usize>(&self, data: String) -> [u8; SIZE] {
         let mut returning_data: [u8; SIZE] = [0; SIZE];
-        let string_bytes = data.into_bytes();
-        returning_data[..string_bytes.len()].copy_from_slice(string_bytes.as_slice());
+        let data = data.split('\0').next().unwrap_or_default();
+        let mut length = data.len().min(SIZE);
+        while !data.is_char_boundary(length) {
+            length -= 1;
+        }
+        returning_data[..length].copy_from_slice(&data.as_bytes()[..length]);
         return returning_data;
     }
+    // Up to the first null, or the first invalid UTF-8
     fn bytes_to_string(&self, data: &[u8]) -> String {
-        let end_pos_result = data.iter().position(|n| n == &0u8);
-        let string_result = match end_pos_result {
-            Some(end_pos) => {
-                String::from_utf8(data[..end_pos].to_vec())
-            },
-            None => {
-                String::from_utf8(data.to_vec())
-            }
-        };
+        let end_pos = data.iter().position(|n| n == &0u8).unwrap_or(data.len());
+        let data = &data[..end_pos];
 
-        return match string_result {
-            Ok(string) => string,
-            Err(err) => {
-                let up_to = err.utf8_error().valid_up_to();
-                self.bytes_to_string(&data[..up_to])
-            },
+        match std::str::from_utf8(data) {
+            Ok(string) => string.to_string(),
+            Err(err) => String::from_utf8_lossy(&data[..err.valid_up_to()]).into_owned(),
         }
     }
+    // Decoders read fixed offsets, short packets are zero filled rather than read past the end
+    fn padded<const SIZE: usize>(&self, data: &[u8]) -> [u8; SIZE] {
+        let mut returning_data: [u8; SIZE] = [0; SIZE];
+        let length = data.len().min(SIZE);
+        returning_data[..length].copy_from_slice(&data[..length]);
+        returning_data
+    }
     fn bytes_to_vec3(&self, data: &[u8]) -> Vector3<f32> {
         let mut pos_x: [u8; 4] = [0; 4];
         pos_x.copy_from_slice(&data[..4]);
@@ -68,15 +71,16 @@ pub trait IPacketTrait {
 
         return returning_data;
     }
+    // Quaternions are x, y, z, w on the wire (sead::Quatf)
     fn bytes_to_quad(&self, data: &[u8]) -> Quaternion<f32> {
-        let mut rot_w: [u8; 4] = [0; 4];
-        rot_w.copy_from_slice(&data[..4]);
         let mut rot_i: [u8; 4] = [0; 4];
-        rot_i.copy_from_slice(&data[4..8]);
+        rot_i.copy_from_slice(&data[..4]);
         let mut rot_j: [u8; 4] = [0; 4];
-        rot_j.copy_from_slice(&data[8..12]);
+        rot_j.copy_from_slice(&data[4..8]);
         let mut rot_k: [u8; 4] = [0; 4];
-        rot_k.copy_from_slice(&data[12..16]);
+        rot_k.copy_from_slice(&data[8..12]);
+        let mut rot_w: [u8; 4] = [0; 4];
+        rot_w.copy_from_slice(&data[12..16]);
         return Quaternion::new(
             f32::from_le_bytes(rot_w),
             f32::from_le_bytes(rot_i),
@@ -87,14 +91,14 @@ pub trait IPacketTrait {
     fn quad_to_bytes(&self, data: Quaternion<f32>) -> [u8; 16] {
         let mut returning_data: [u8; 16] = [0x0; 16];
 
-        let rotation_w_bytes = data.w.to_le_bytes();
-        returning_data[..4].copy_from_slice(&rotation_w_bytes);
         let rotation_i_bytes = data.i.to_le_bytes();
-        returning_data[4..8].copy_from_slice(&rotation_i_bytes);
+        returning_data[..4].copy_from_slice(&rotation_i_bytes);
         let rotation_j_bytes = data.j.to_le_bytes();
-        returning_data[8..12].copy_from_slice(&rotation_j_bytes);
+        returning_data[4..8].copy_from_slice(&rotation_j_bytes);
         let rotation_k_bytes = data.k.to_le_bytes();
-        returning_data[12..16].copy_from_slice(&rotation_k_bytes);
+        returning_data[8..12].copy_from_slice(&rotation_k_bytes);
+        let rotation_w_bytes = data.w.to_le_bytes();
+        returning_data[12..16].copy_from_slice(&rotation_w_bytes);
 
         return returning_data;
     }
@@ -30,6 +30,7 @@ impl IPacketTrait for IPacket<InitPacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         let mut arr: [u8; 2] = [0; 2];
         arr.copy_from_slice(&data[..SIZE]);
         self.packet.max_players = u16::from_le_bytes(arr);
@@ -6,7 +6,7 @@ use nalgebra::*;
 
 const SIZE: usize = 0x38;
 const FLOAT32_SIZE: usize = 4;
-const ANIMATION_WEIGHT_SIZE: usize = 24 / FLOAT32_SIZE;
+pub const ANIMATION_WEIGHT_SIZE: usize = 24 / FLOAT32_SIZE;
 const ACT_SIZE: usize = 0x20;
 const SUB_ACT_SIZE: usize = 0x10;
 
@@ -69,6 +69,7 @@ impl IPacketTrait for IPacket<PlayerPacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         self.packet.position = self.bytes_to_vec3(&data[..12]);
         self.packet.rotation = self.bytes_to_quad(&data[12..28]);
 
@@ -30,6 +30,7 @@ impl IPacketTrait for IPacket<ShinePacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
+        let data = &self.padded::<SIZE>(data);
         let mut arr: [u8; SIZE] = [0; SIZE];
         arr.copy_from_slice(&data[..SIZE]);
         self.packet.shine_id = u32::from_le_bytes(arr);
@@ -3,11 +3,13 @@ use crate::packet::packets::IPacket::{
     IPacket
 };
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 #[repr(u8)]
 pub enum TagUpdate {
     Time = 0x1,
     State = 0x2,
+    // Both flags, sent when the whole state changes
+    Both = 0x3,
 }
 
 pub struct TagPacket {
@@ -40,11 +42,8 @@ impl IPacketTrait for IPacket<TagPacket> {
     fn serialize(&self) -> [u8; 1024] {
         let mut returning_data: [u8; 1024] = [0x0; 1024];
         
-        match self.packet.update_type {
-            TagUpdate::Time => returning_data[0] = 1,
-            TagUpdate::State => returning_data[0] = 0,
-        }
-        
+        returning_data[0] = self.packet.update_type as u8;
+
         returning_data[1] = self.bool_to_byte(self.packet.is_it);
         returning_data[2] = self.packet.seconds;
         returning_data[4..SIZE].copy_from_slice(&u16::to_le_bytes(self.packet.minutes));
@@ -52,10 +51,12 @@ impl IPacketTrait for IPacket<TagPacket> {
         return returning_data;
     }
     fn deserialize(&mut self, data: &[u8]) {
-        if data[0] == 1 {
-            self.packet.update_type = TagUpdate::Time;
-        } else if data[0] == 2 {
-            self.packet.update_type = TagUpdate::State;
+        let data = &self.padded::<SIZE>(data);
+        match data[0] {
+            1 => self.packet.update_type = TagUpdate::Time,
+            2 => self.packet.update_type = TagUpdate::State,
+            3 => self.packet.update_type = TagUpdate::Both,
+            _ => {},
         }
 
         self.packet.is_it = self.byte_to_bool(data[1]);
@@ -1,3 +1,5 @@
+// Named after the packet types of the C# server
+#![allow(non_snake_case)]
 pub mod IPacket;
 pub mod GamePacket;
 pub mod InitPacket;
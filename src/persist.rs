@@ -0,0 +1,85 @@
+//! State kept across restarts, see the `persist_*` settings. Everything is written on shutdown,
+//! shines and bans are read back when the server is created
+use std::{
+    fs,
+    io,
+    sync::Arc
+};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use crate::{
+    metrics::METRICS,
+    server::Server,
+    settings::{BannedPlayers, PersistTable, Settings},
+    shines::ShineBag
+};
+
+/// Fills the shine bag and replaces the bans in `settings` with what was saved, missing files are skipped
+pub fn load(settings: &mut Settings, shines: &ShineBag) {
+    if let Some(saved) = read::<Vec<u32>>(&settings.persist_shines) {
+        info!(file_name = %settings.persist_shines.file_name, shines = saved.len(), "Loaded shines");
+        for shine_id in saved {
+            shines.insert(shine_id);
+        }
+    }
+    if let Some(saved) = read::<BannedPlayers>(&settings.persist_bans) {
+        info!(file_name = %settings.persist_bans.file_name, players = saved.players.len(), ip_addresses = saved.ip_addresses.len(), "Loaded bans");
+        settings.banned_players = saved;
+    }
+}
+
+/// Writes every enabled file, a failed write is logged and doesn't stop the others
+pub async fn save(server: Arc<RwLock<Server>>) {
+    let (shines, bans, persist_shines, persist_bans, persist_stats) = {
+        let s = server.read().await;
+        (
+            json(&s.shines.all()),
+            json(&s.settings.banned_players),
+            s.settings.persist_shines.enabled.then(|| s.settings.persist_shines.file_name.to_string()),
+            s.settings.persist_bans.enabled.then(|| s.settings.persist_bans.file_name.to_string()),
+            s.settings.persist_stats.enabled.then(|| s.settings.persist_stats.file_name.to_string()),
+        )
+    };
+
+    if let Some(file_name) = persist_shines {
+        write(&file_name, shines.as_bytes());
+    }
+    if let Some(file_name) = persist_bans {
+        write(&file_name, bans.as_bytes());
+    }
+    if let Some(file_name) = persist_stats {
+        write(&file_name, METRICS.render(server).await.as_bytes());
+    }
+}
+
+fn read<T: DeserializeOwned>(persist: &PersistTable) -> Option<T> {
+    if !persist.enabled {
+        return None;
+    }
+
+    let result = fs::read(&persist.file_name)
+        .and_then(|contents| serde_json::from_slice(&contents).map_err(io::Error::from));
+    match result {
+        Ok(saved) => Some(saved),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => {
+            error!(file_name = %persist.file_name, %error, "Failed to load saved state");
+            None
+        }
+    }
+}
+
+fn json(value: &impl Serialize) -> String {
+    serde_json::to_string_pretty(value).unwrap()
+}
+
+// Written next to the file then renamed over it, so being killed part way through leaves the old file
+fn write(file_name: &str, contents: &[u8]) {
+    let temporary = format!("{}.tmp", file_name);
+    let result = fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, file_name));
+    match result {
+        Ok(()) => info!(file_name, "Saved"),
+        Err(error) => error!(file_name, %error, "Failed to save"),
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, Ordering}
+    }
+};
+use chashmap::CHashMap;
+use tokio::sync::{
+    RwLock,
+    mpsc::Sender
+};
+use uuid::Uuid;
+use crate::{
+    capture::{self, Direction},
+    client::{self, Client},
+    events::{EventBus, ServerEvent},
+    metrics::METRICS
+};
+
+/// Why a player left, counted by the metrics endpoint
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DisconnectReason {
+    // The game closed the socket
+    Closed,
+    ReadError,
+    // The writer task stopped or the send queue was full
+    SendFailed,
+    Kicked,
+    // Another connection joined with the same UUID
+    Replaced,
+    // The server was shut down through its `ServerHandle`
+    Shutdown,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::SendFailed => "send_failed",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Everything a broadcast needs without locking the client
+#[derive(Clone)]
+pub struct PlayerEntry {
+    pub id: Uuid,
+    pub name: String,
+    pub slot: usize,
+    pub connected: Arc<AtomicBool>,
+    pub connection: u32,
+    pub outgoing: Sender<Vec<u8>>,
+    pub client: Arc<RwLock<Client>>,
+}
+
+impl PlayerEntry {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Queues a frame for the writer task, fails when the client is gone or too far behind
+    pub fn send_frame(&self, frame: &[u8]) -> bool {
+        let sent = self.outgoing.try_send(frame.to_vec()).is_ok();
+        if sent {
+            capture::record(Direction::Outbound, self.connection, self.id, frame);
+        }
+        sent
+    }
+
+    /// Closes the socket once what's queued for it is written, which ends the writer task
+    pub fn close(&self) {
+        client::close_queue(&self.outgoing);
+    }
+}
+
+/// Connected and recently disconnected players keyed by UUID.
+/// A player keeps their slot for the lifetime of the server so reconnects land in the same place.
+pub struct PlayerRegistry {
+    players: CHashMap<Uuid, PlayerEntry>,
+    slots: Mutex<Vec<Uuid>>,
+    // Only the connected players by slot, so broadcasts don't go through everyone ever seen
+    online: Mutex<BTreeMap<usize, PlayerEntry>>,
+    events: EventBus,
+}
+
+impl PlayerRegistry {
+    /// Joins and leaves are published to `events`
+    pub fn new(events: EventBus) -> Self {
+        PlayerRegistry {
+            players: CHashMap::new(),
+            slots: Mutex::new(Vec::new()),
+            online: Mutex::new(BTreeMap::new()),
+            events,
+        }
+    }
+
+    /// Adds the client or replaces an earlier connection with the same UUID
+    pub async fn join(&self, client: Arc<RwLock<Client>>) -> PlayerEntry {
+        let (id, name, connected, connection, outgoing) = {
+            let c = client.read().await;
+            (c.id, c.name.to_string(), c.connected.clone(), c.connection, c.outgoing.clone())
+        };
+        connected.store(true, Ordering::Release);
+
+        let (slot, reconnect) = {
+            let mut slots = self.slots.lock().unwrap();
+            match slots.iter().position(|slot_id| *slot_id == id) {
+                Some(slot) => (slot, true),
+                None => {
+                    slots.push(id);
+                    (slots.len() - 1, false)
+                }
+            }
+        };
+
+        let entry = PlayerEntry {
+            id,
+            name: name.to_string(),
+            slot,
+            connected,
+            connection,
+            outgoing,
+            client,
+        };
+        self.online.lock().unwrap().insert(slot, entry.clone());
+        if let Some(previous) = self.players.insert(id, entry.clone()) {
+            if !Arc::ptr_eq(&previous.connected, &entry.connected) {
+                if previous.connected.swap(false, Ordering::AcqRel) {
+                    METRICS.disconnect(DisconnectReason::Replaced);
+                }
+                previous.close();
+            }
+        }
+
+        self.events.publish(ServerEvent::PlayerJoined { id, name, slot, reconnect });
+        entry
+    }
+
+    /// Marks the connection behind `connected` as gone, a newer connection with the same UUID is left alone
+    pub fn disconnect(&self, id: &Uuid, connected: &Arc<AtomicBool>, reason: DisconnectReason) {
+        let entry = match self.get(id) {
+            Some(entry) if Arc::ptr_eq(&entry.connected, connected) => entry,
+            _ => {
+                connected.store(false, Ordering::Release);
+                return;
+            },
+        };
+
+        {
+            let mut online = self.online.lock().unwrap();
+            if online.get(&entry.slot).is_some_and(|online| Arc::ptr_eq(&online.connected, connected)) {
+                online.remove(&entry.slot);
+            }
+        }
+        if entry.connected.swap(false, Ordering::AcqRel) {
+            entry.close();
+            METRICS.disconnect(reason);
+            self.events.publish(ServerEvent::PlayerLeft {
+                id: entry.id,
+                name: entry.name.to_string(),
+                slot: entry.slot,
+                reason,
+            });
+        }
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<PlayerEntry> {
+        self.players.get(id).map(|entry| entry.clone())
+    }
+
+    /// Every known player in slot order, including disconnected ones
+    pub fn snapshot(&self) -> Vec<PlayerEntry> {
+        let ids: Vec<Uuid> = self.slots.lock().unwrap().clone();
+        ids.iter()
+            .filter_map(|id| self.get(id))
+            .collect()
+    }
+
+    /// Connected players in slot order
+    pub fn connected(&self) -> Vec<PlayerEntry> {
+        self.online.lock().unwrap()
+            .values()
+            .filter(|entry| entry.is_connected())
+            .cloned()
+            .collect()
+    }
+
+    /// Connected players other than `id`
+    pub fn others(&self, id: &Uuid) -> Vec<PlayerEntry> {
+        self.online.lock().unwrap()
+            .values()
+            .filter(|entry| entry.id != *id && entry.is_connected())
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+//! Applies changes to the settings file while the server runs. The file is checked for changes,
+//! the binary also reloads on SIGHUP, and the `settings reload` console command reloads straight away
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime}
+};
+use serde_json::Value;
+use tokio::{
+    sync::RwLock,
+    time::{interval, MissedTickBehavior}
+};
+use tracing::{info, warn};
+use crate::{
+    server::{Server, ServerWrapper},
+    settings::{BannedPlayers, Settings, SettingsOverrides}
+};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// Settings only read on startup, matched against the start of a changed setting's path
+const NEEDS_RESTART: [&str; 10] = [
+    "server.address", "server.port", "smoothing.tick_rate", "tick.tick_rate", "admin.enabled", "admin.address", "admin.token",
+    "metrics", "logging", "capture",
+];
+
+/// The file the live settings came from
+pub struct SettingsFile {
+    pub path: PathBuf,
+    // Applied again on every reload so they keep winning over the file
+    pub overrides: SettingsOverrides,
+    // When the file was last read, so an unchanged file isn't read again
+    loaded_at: Mutex<Option<SystemTime>>,
+    // The file's bans when it was last read, bans that differ from these were changed while running
+    bans: Mutex<BannedPlayers>,
+}
+
+impl SettingsFile {
+    /// Call once `settings` have been read from `path`, before saved bans are loaded over them
+    pub fn new(path: impl AsRef<Path>, overrides: SettingsOverrides, settings: &Settings) -> SettingsFile {
+        let file = SettingsFile {
+            path: path.as_ref().to_path_buf(),
+            overrides,
+            loaded_at: Mutex::new(None),
+            bans: Mutex::new(settings.banned_players.clone()),
+        };
+        *file.loaded_at.lock().unwrap() = file.last_modified();
+        file
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    fn changed(&self) -> bool {
+        *self.loaded_at.lock().unwrap() != self.last_modified()
+    }
+
+    fn read(&self) -> Result<Settings, String> {
+        *self.loaded_at.lock().unwrap() = self.last_modified();
+        Settings::read(&self.path, &self.overrides).map_err(|error| error.to_string())
+    }
+
+    /// Applies bans made or lifted while running, through the admin API, the console or `persist_bans`,
+    /// over the bans in `settings` just read from the file
+    fn keep_bans(&self, live: &BannedPlayers, settings: &mut Settings) {
+        let mut read = self.bans.lock().unwrap();
+        let file = settings.banned_players.clone();
+        merge(&mut settings.banned_players.players, &read.players, &live.players);
+        merge(&mut settings.banned_players.ip_addresses, &read.ip_addresses, &live.ip_addresses);
+        *read = file;
+    }
+}
+
+// Adds what `live` gained over `read` to `file` and takes out what it lost
+fn merge<T: Clone + PartialEq>(file: &mut Vec<T>, read: &[T], live: &[T]) {
+    file.retain(|item| live.contains(item) || !read.contains(item));
+    for item in live {
+        if !read.contains(item) && !file.contains(item) {
+            file.push(item.clone());
+        }
+    }
+}
+
+pub struct SettingsReloader;
+
+impl SettingsReloader {
+    /// Reloads whenever the file changes, does nothing unless the server has a settings file
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let Some(file) = server.read().await.settings_file.clone() else {
+            return;
+        };
+
+        let mut reload_check = interval(RELOAD_CHECK_INTERVAL);
+        reload_check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            reload_check.tick().await;
+            if !file.changed() {
+                continue;
+            }
+            match SettingsReloader::reload(server.clone()).await {
+                Ok(message) => info!("{}", message),
+                Err(message) => warn!("{}", message),
+            }
+        }
+    }
+
+    /// Reads the settings file again and swaps in the new settings, an invalid file leaves the live ones alone.
+    /// Bans made or lifted since the file was last read are kept. Connected players who can't join anymore are kicked.
+    /// Returns what changed
+    pub async fn reload(server: Arc<RwLock<Server>>) -> Result<String, String> {
+        let file = server.read().await.settings_file.clone()
+            .ok_or("The server wasn't started from a settings file")?;
+        let mut settings = file.read().map_err(|error| format!("Kept the current settings, {}", error))?;
+
+        let changes = {
+            let mut s = server.write().await;
+            file.keep_bans(&s.settings.banned_players, &mut settings);
+            let changes = changed_settings(&s.settings, &settings);
+            if !changes.is_empty() {
+                s.settings = settings;
+            }
+            changes
+        };
+        if changes.is_empty() {
+            return Ok("Settings are unchanged".to_string());
+        }
+
+        let mut message = format!("Reloaded settings, changed {}", changes.join(", "));
+        let restart: Vec<&str> = changes.iter()
+            .filter(|change| NEEDS_RESTART.iter().any(|prefix| change.starts_with(prefix)))
+            .map(|change| change.as_str())
+            .collect();
+        if !restart.is_empty() {
+            message += &format!(". Restart the server to apply {}", restart.join(", "));
+        }
+        let kicked = ServerWrapper::enforce_access(server).await;
+        if !kicked.is_empty() {
+            message += &format!(". Kicked {}, who can't join anymore", kicked.join(", "));
+        }
+        Ok(message)
+    }
+}
+
+/// Dotted paths of every setting that differs, lists are compared as a whole
+fn changed_settings(old: &Settings, new: &Settings) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff("", &serde_json::to_value(old).unwrap(), &serde_json::to_value(new).unwrap(), &mut changes);
+    changes
+}
+
+fn diff(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, value) in new {
+                let path = if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+                diff(&path, old.get(key).unwrap_or(&Value::Null), value, changes);
+            }
+        },
+        _ if old != new => changes.push(path.to_string()),
+        _ => {},
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime}
+};
+use rhai::{
+    Array,
+    CallFnOptions,
+    Dynamic,
+    Engine,
+    FuncArgs,
+    Map,
+    Scope,
+    AST
+};
+use tokio::{
+    sync::{RwLock, broadcast},
+    time::{interval, MissedTickBehavior}
+};
+use tracing::{info, warn};
+use crate::{
+    client::Client,
+    events::ServerEvent,
+    server::{Server, ServerWrapper},
+    stages
+};
+
+pub const SCRIPTS_DIR: &str = "./scripts";
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// Scripts run on the server's runtime, so a runaway one is stopped instead of hanging it
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+// Queued by the functions scripts call, run once the script returns
+enum ScriptCommand {
+    Warp { selector: String, target: String, scenario: i8 },
+    Tag { selector: String, is_it: bool },
+    Kick { selector: String },
+    Message { script: String, text: String },
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    scope: Scope<'static>,
+    // `this` in every callback, survives between events but not reloads
+    state: Dynamic,
+}
+
+/// Runs the `*.rhai` files in a directory.
+///
+/// Scripts define any of `on_join(id, name)`, `on_leave(id, name)`, `on_stage(id, stage, scenario)`,
+/// `on_shine(id, shine_id)`, `on_costume(id, body, cap)`, `on_capture(id, model)`, `on_tag(id, is_it)`
+/// and `on_caught(id)`, and can call `players()`, `player(selector)`, `warp(selector, stage[, scenario])`,
+/// `tag(selector, is_it)`, `kick(selector)` and `message(text)`.
+/// Files are reloaded when they change or on the `scripts reload` console command.
+/// A script or callback that runs too long, recurses too deep or builds huge values is stopped with an error.
+pub struct ScriptHost {
+    dir: PathBuf,
+    engine: Engine,
+    scripts: Mutex<Vec<Script>>,
+    loaded_at: Mutex<Option<SystemTime>>,
+    commands: Arc<Mutex<Vec<ScriptCommand>>>,
+    // Refreshed before every event so scripts can read player state without locking clients
+    players: Arc<Mutex<Array>>,
+}
+
+impl ScriptHost {
+    pub fn new(dir: &Path) -> ScriptHost {
+        let commands: Arc<Mutex<Vec<ScriptCommand>>> = Arc::new(Mutex::new(Vec::new()));
+        let players: Arc<Mutex<Array>> = Arc::new(Mutex::new(Array::new()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_COLLECTION_SIZE);
+        engine.set_max_map_size(MAX_COLLECTION_SIZE);
+
+        let queue = commands.clone();
+        engine.register_fn("warp", move |selector: &str, target: &str| {
+            queue.lock().unwrap().push(ScriptCommand::Warp { selector: selector.to_string(), target: target.to_string(), scenario: stages::KEEP_SCENARIO });
+        });
+        let queue = commands.clone();
+        engine.register_fn("warp", move |selector: &str, target: &str, scenario: i64| {
+            let scenario = i8::try_from(scenario).unwrap_or(i8::MIN);
+            queue.lock().unwrap().push(ScriptCommand::Warp { selector: selector.to_string(), target: target.to_string(), scenario });
+        });
+        let queue = commands.clone();
+        engine.register_fn("tag", move |selector: &str, is_it: bool| {
+            queue.lock().unwrap().push(ScriptCommand::Tag { selector: selector.to_string(), is_it });
+        });
+        let queue = commands.clone();
+        engine.register_fn("kick", move |selector: &str| {
+            queue.lock().unwrap().push(ScriptCommand::Kick { selector: selector.to_string() });
+        });
+
+        let queue = commands.clone();
+        engine.register_fn("message", move |text: &str| {
+            queue.lock().unwrap().push(ScriptCommand::Message { script: String::new(), text: text.to_string() });
+        });
+
+        let snapshot = players.clone();
+        engine.register_fn("players", move || -> Array {
+            snapshot.lock().unwrap().clone()
+        });
+        let snapshot = players.clone();
+        engine.register_fn("player", move |selector: &str| -> Dynamic {
+            snapshot.lock().unwrap()
+                .iter()
+                .find(|player| {
+                    let player = player.read_lock::<Map>().unwrap();
+                    let matches = |key: &str| player.get(key).is_some_and(|value| value.to_string().eq_ignore_ascii_case(selector));
+                    matches("id") || matches("name")
+                })
+                .cloned()
+                .unwrap_or(Dynamic::UNIT)
+        });
+
+        ScriptHost {
+            dir: dir.to_path_buf(),
+            engine,
+            scripts: Mutex::new(Vec::new()),
+            loaded_at: Mutex::new(None),
+            commands,
+            players,
+        }
+    }
+
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let (host, mut events) = {
+            let s = server.read().await;
+            (s.scripts.clone(), s.events.subscribe())
+        };
+        info!("{}", host.reload());
+
+        let mut reload_check = interval(RELOAD_CHECK_INTERVAL);
+        reload_check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => host.dispatch(server.clone(), event).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!(skipped, "Scripts fell behind and skipped events"),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = reload_check.tick() => {
+                    if host.changed() {
+                        info!("{}", host.reload());
+                    }
+                },
+            }
+        }
+    }
+
+    fn script_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "rhai"))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        files.sort();
+        files
+    }
+
+    // Newest modification time of the directory and its scripts, catches edits, additions and removals
+    fn last_modified(&self) -> Option<SystemTime> {
+        std::iter::once(self.dir.clone())
+            .chain(self.script_files())
+            .filter_map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+            .max()
+    }
+
+    fn changed(&self) -> bool {
+        *self.loaded_at.lock().unwrap() != self.last_modified()
+    }
+
+    /// Compiles and runs every script again, a script that fails to load is skipped
+    pub fn reload(&self) -> String {
+        *self.loaded_at.lock().unwrap() = self.last_modified();
+
+        let mut scripts: Vec<Script> = Vec::new();
+        let mut errors: Vec<String> = Vec::new();
+        for path in self.script_files() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let ast = match self.engine.compile_file(path.clone()) {
+                Ok(ast) => ast,
+                Err(error) => {
+                    errors.push(format!("{}: {}", name, error));
+                    continue;
+                }
+            };
+
+            let mut scope = Scope::new();
+            if let Err(error) = self.engine.run_ast_with_scope(&mut scope, &ast) {
+                errors.push(format!("{}: {}", name, error));
+                continue;
+            }
+
+            scripts.push(Script {
+                name,
+                ast,
+                scope,
+                state: Dynamic::from_map(Map::new()),
+            });
+        }
+
+        let names: Vec<String> = scripts.iter().map(|script| script.name.to_string()).collect();
+        *self.scripts.lock().unwrap() = scripts;
+
+        let mut message = format!("Loaded {} script(s) from {}: {}", names.len(), self.dir.display(), names.join(", "));
+        for error in errors {
+            message.push_str(&format!("\nFailed to load {}", error));
+        }
+        message
+    }
+
+    async fn dispatch(&self, server: Arc<RwLock<Server>>, event: ServerEvent) {
+        let (callback, args): (&str, Vec<Dynamic>) = match event {
+            ServerEvent::PlayerJoined { id, name, .. } => ("on_join", vec![id.to_string().into(), name.into()]),
+            ServerEvent::PlayerLeft { id, name, .. } => ("on_leave", vec![id.to_string().into(), name.into()]),
+            ServerEvent::StageChanged { id, stage, scenario, .. } => ("on_stage", vec![id.to_string().into(), stage.into(), (scenario as i64).into()]),
+            ServerEvent::ShineCollected { id, shine_id } => ("on_shine", vec![id.to_string().into(), (shine_id as i64).into()]),
+            ServerEvent::CostumeChanged { id, body, cap } => ("on_costume", vec![id.to_string().into(), body.into(), cap.into()]),
+            ServerEvent::CaptureChanged { id, model } => ("on_capture", vec![id.to_string().into(), model.into()]),
+            ServerEvent::TagStateChanged { id, is_it } => ("on_tag", vec![id.to_string().into(), is_it.into()]),
+            ServerEvent::Caught { id } => ("on_caught", vec![id.to_string().into()]),
+        };
+
+        if !self.defines(callback, args.len()) {
+            return;
+        }
+
+        let players = ScriptHost::player_maps(server.clone()).await;
+        *self.players.lock().unwrap() = players;
+        self.call(callback, args);
+
+        let commands: Vec<ScriptCommand> = self.commands.lock().unwrap().drain(..).collect();
+        for command in commands {
+            if let Err(error) = ScriptHost::run(server.clone(), command).await {
+                warn!(%error, "Script command failed");
+            }
+        }
+    }
+
+    fn defines(&self, callback: &str, arity: usize) -> bool {
+        self.scripts.lock().unwrap()
+            .iter()
+            .any(|script| script.ast.iter_functions().any(|function| function.name == callback && function.params.len() == arity))
+    }
+
+    fn call(&self, callback: &str, args: impl FuncArgs + Clone) {
+        let mut scripts = self.scripts.lock().unwrap();
+        for script in scripts.iter_mut() {
+            if !script.ast.iter_functions().any(|function| function.name == callback) {
+                continue;
+            }
+
+            let options = CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut script.state);
+            if let Err(error) = self.engine.call_fn_with_options::<Dynamic>(options, &mut script.scope, &script.ast, callback, args.clone()) {
+                warn!(script = %script.name, callback, %error, "Script callback failed");
+            }
+
+            // Messages are tagged with the script that sent them
+            for command in self.commands.lock().unwrap().iter_mut() {
+                if let ScriptCommand::Message { script: sender, .. } = command {
+                    if sender.is_empty() {
+                        *sender = script.name.to_string();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn player_maps(server: Arc<RwLock<Server>>) -> Array {
+        let players = server.read().await.players.clone();
+        let mut maps = Array::new();
+        for player in players.connected() {
+            maps.push(Dynamic::from_map(ScriptHost::player_map(&*player.client.read().await)));
+        }
+        maps
+    }
+
+    fn player_map(client: &Client) -> Map {
+        let mut map = Map::new();
+        map.insert("id".into(), client.id.to_string().into());
+        map.insert("name".into(), client.name.to_string().into());
+        map.insert("stage".into(), match &client.metadata.last_game_packet {
+            Some(game_packet) => game_packet.packet.stage.to_string().into(),
+            None => Dynamic::UNIT,
+        });
+        map.insert("kingdom".into(), match client.metadata.kingdom {
+            Some(kingdom) => kingdom.to_string().into(),
+            None => Dynamic::UNIT,
+        });
+        map.insert("scenario".into(), (client.metadata.scenario as i64).into());
+        map.insert("is_2d".into(), client.metadata.is_2d.into());
+        map.insert("seeking".into(), client.metadata.seeking.into());
+        map.insert("loaded_save".into(), client.metadata.loaded_save.into());
+        map.insert("costume".into(), match &client.current_costume {
+            Some(costume) => {
+                let mut costume_map = Map::new();
+                costume_map.insert("body".into(), costume.packet.body_name.to_string().into());
+                costume_map.insert("cap".into(), costume.packet.cap_name.to_string().into());
+                costume_map.into()
+            },
+            None => Dynamic::UNIT,
+        });
+        map
+    }
+
+    async fn run(server: Arc<RwLock<Server>>, command: ScriptCommand) -> Result<(), String> {
+        match command {
+            ScriptCommand::Warp { selector, target, scenario } => {
+                let (stage, scenario) = stages::resolve_warp_target(&target, scenario)?;
+                for c in ServerWrapper::find_clients(server.clone(), &selector).await {
+                    ServerWrapper::warp(server.clone(), c, &stage, "", scenario).await;
+                }
+            },
+            ScriptCommand::Tag { selector, is_it } => {
+                for c in ServerWrapper::find_clients(server.clone(), &selector).await {
+                    ServerWrapper::send_tag_state(server.clone(), c, is_it).await;
+                }
+            },
+            ScriptCommand::Kick { selector } => {
+                for c in ServerWrapper::find_clients(server.clone(), &selector).await {
+                    ServerWrapper::kick(server.clone(), c).await;
+                }
+            },
+            // The game has no chat, messages go to the server log
+            ScriptCommand::Message { script, text } => info!(%script, "{}", text),
+        }
+        Ok(())
+    }
+}
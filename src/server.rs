@@ -1,31 +1,58 @@
+//! Accepts connections and relays packets between players. [`Server`] is the shared state,
+//! [`ServerWrapper`] has the functions that act on it, they all take the server behind an `Arc<RwLock<_>>`
 use std::{
+    collections::HashMap,
+    net::SocketAddr,
     sync::{
         Arc,
+        atomic::Ordering
     }, 
-    future::Future
+    time::{Duration, Instant}
 };
-use nalgebra::{Vector3, Quaternion};
 use tokio::{
     net::{
         TcpStream,
         TcpListener
     },
     io::{
+        self,
+        AsyncRead,
         AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
         Result
     },
-    sync::{Mutex, RwLock}
+    sync::RwLock,
+    task::{JoinHandle, JoinSet},
+    time
 };
 use chrono::{
     Utc
 };
+use tracing::{
+    Instrument,
+    Span,
+    debug,
+    field,
+    info,
+    info_span,
+    trace,
+    warn
+};
+use uuid::Uuid;
 use mempool::Pool;
 use crate::{
     client::{
         Client,
-        ClientTraits, Time
+        ClientTraits, Time,
+        encode_frame
     },
+    registry::{PlayerRegistry, PlayerEntry, DisconnectReason},
+    metrics::{METRICS, DecodeError, MetricsServer},
+    hooks::{HookChain, HookPacket, PlayerView},
+    events::{EventBus, ServerEvent},
     packet::{
+        framing::{Frame, FrameDecoder},
         PacketHeader::{
             PacketHeader,
             SIZE as PACKET_HEADER_SIZE
@@ -60,291 +87,525 @@ use crate::{
         packet_to_type_map, type_to_packet_map
     },
     settings::{
-        MAX_PLAYERS, 
         Settings, 
-        FlipOptions,
     }, 
-    lib::rot::QuaternionMatrixConvertible
+    stages::{
+        self,
+        Kingdom
+    },
+    smoothing::Smoother,
+    tick::TickLoop,
+    shines::{ShineBag, ShineSync, SPEEDRUN_SYNC_DELAY},
+    capture::{self, Direction, Record},
+    persist,
+    reload::{SettingsFile, SettingsReloader}
 };
+#[cfg(feature = "scripting")]
+use crate::scripting::{ScriptHost, SCRIPTS_DIR};
+#[cfg(feature = "admin-api")]
+use crate::admin::AdminApi;
+
+// Time left for replayed connections to finish after the last record
+const REPLAY_DRAIN: Duration = Duration::from_secs(1);
 
 pub struct ServerWrapper {
     pub server: Arc<RwLock<Server>>
 }
 
+// Aborts the background tasks when `ServerWrapper::start` returns or is dropped
+struct BackgroundTasks(Vec<JoinHandle<()>>);
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Everything shared between connections
 pub struct Server {
-    pub clients: Vec<Arc<RwLock<Client>>>,
+    pub players: Arc<PlayerRegistry>,
+    pub events: EventBus,
+    pub hooks: HookChain,
+    pub shines: Arc<ShineBag>,
+    #[cfg(feature = "scripting")]
+    pub scripts: Arc<ScriptHost>,
     pub mempool: Pool<[u8; 1024]>,
     pub settings: Settings,
+    // Where `settings` came from, `None` when they were built in code
+    pub settings_file: Option<Arc<SettingsFile>>,
+    // UUIDs or names let in from the console while `allowed_players` is on, forgotten on restart
+    pub guests: Vec<String>,
+}
+
+impl Server {
+    /// Nothing runs until `ServerWrapper::start`, saved shines and bans are loaded here
+    pub fn new(mut settings: Settings) -> Self {
+        let events = EventBus::new();
+        let shines = ShineBag::new();
+        persist::load(&mut settings, &shines);
+        Server {
+            players: Arc::new(PlayerRegistry::new(events.clone())),
+            events,
+            hooks: HookChain::defaults(),
+            shines: Arc::new(shines),
+            #[cfg(feature = "scripting")]
+            scripts: Arc::new(ScriptHost::new(std::path::Path::new(SCRIPTS_DIR))),
+            mempool: Pool::new(Box::new(|| [0; 1024])),
+            settings,
+            settings_file: None,
+            guests: Vec::new(),
+        }
+    }
 }
 
 impl ServerWrapper {
+    /// Starts the background tasks, then serves every connection made to the listener until accepting fails.
+    /// Dropping the future stops the background tasks and every connection, see `ServerHandle::shutdown`
     pub async fn start(server: Arc<RwLock<Server>>, listener: TcpListener) -> Result<()> {
-        println!("start");
+        let _tasks = BackgroundTasks(ServerWrapper::spawn_tasks(server.clone()).await);
+        let mut connections = JoinSet::new();
+
         // Loop until new connection is made and spawn an async event loop
         loop {
             let (socket, socket_addr) = listener.accept().await?;
-            println!("new client: {:?}", socket_addr.to_string());
+            debug!(address = %socket_addr, "New connection");
+            while connections.try_join_next().is_some() {}
+
+            if server.read().await.settings.banned_players.bans_address(&socket_addr.ip()) {
+                info!(address = %socket_addr.ip(), "Refusing banned address");
+                continue;
+            }
 
             let local_server = server.clone();
-            tokio::spawn(async move {
-                // ServerWrapper::handle_socket(local_server.clone(), socket).await
-                ServerWrapper::handle_request(local_server.clone(), socket).await
+            connections.spawn(async move {
+                ServerWrapper::handle_socket(local_server.clone(), socket, socket_addr).await
             });
         }
     }
 
-    async fn handle_request(server: Arc<RwLock<Server>>, socket: TcpStream) {
-        println!("handle_request");
-        let mut first_connection = true;
+    /// Everything that runs alongside the connections, also used by replays. The tasks keep running if the handles are dropped
+    pub async fn spawn_tasks(server: Arc<RwLock<Server>>) -> Vec<JoinHandle<()>> {
+        vec![
+            tokio::spawn(EventBus::log(server.read().await.events.subscribe())),
+            tokio::spawn(Smoother::start(server.clone())),
+            tokio::spawn(TickLoop::start(server.clone())),
+            tokio::spawn(ShineSync::start(server.clone())),
+            tokio::spawn(MetricsServer::start(server.clone())),
+            tokio::spawn(SettingsReloader::start(server.clone())),
+            #[cfg(feature = "scripting")]
+            tokio::spawn(ScriptHost::start(server.clone())),
+            #[cfg(feature = "admin-api")]
+            tokio::spawn(AdminApi::start(server.clone())),
+        ]
+    }
 
-        let socket_mutex = Arc::new(
-            Mutex::new(
-                socket
-            )
-        );
+    async fn handle_socket(server: Arc<RwLock<Server>>, socket: TcpStream, address: SocketAddr) {
+        // Reading stays on this task, writes go through the client's queue so they never wait on a read
+        let (reader, writer) = socket.into_split();
+        ServerWrapper::handle_connection(server, reader, writer, address, capture::next_connection()).await
+    }
+
+    /// Serves one game connection over any socket, replays use in-memory ones
+    pub async fn handle_connection<R, W>(server: Arc<RwLock<Server>>, reader: R, writer: W, address: SocketAddr, connection: u32)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static
+    {
         let client = Arc::new(
             RwLock::new(
-                Client::new(socket_mutex)
+                Client::new(Client::spawn_writer(writer))
             )
         );
+        {
+            let mut c = client.write().await;
+            c.address = Some(address);
+            c.connection = connection;
+        }
+
+        // uuid and player are filled in by the ConnectPacket
+        let span = info_span!("client", ip = %address.ip(), connection, uuid = field::Empty, player = field::Empty);
+        ServerWrapper::handle_request(server, client.clone(), reader).instrument(span).await;
+
+        capture::record(Direction::Closed, connection, client.read().await.id, &[]);
+    }
+
+    /// Feeds the inbound frames of a capture to the server over in-memory sockets, keeping the original timing.
+    /// Every read the server made is written separately so packets are split the same way.
+    pub async fn replay(server: Arc<RwLock<Server>>, records: Vec<Record>) {
+        let started = time::Instant::now();
+        let mut sockets = HashMap::new();
+        let mut replayed = 0;
+
+        for record in records {
+            match record.direction {
+                Direction::Inbound => {
+                    let socket = sockets.entry(record.connection).or_insert_with(|| {
+                        let (client_side, server_side) = io::duplex(64 * 1024);
+                        let (reader, writer) = io::split(server_side);
+                        let address = SocketAddr::from(([127, 0, 0, 1], record.connection as u16));
+                        tokio::spawn(ServerWrapper::handle_connection(server.clone(), reader, writer, address, capture::next_connection()));
+
+                        // Whatever the server sends back is only kept if this run is captured too
+                        let (mut replies, requests) = io::split(client_side);
+                        tokio::spawn(async move {
+                            let mut buffer = [0; 1024];
+                            while let Ok(1..) = replies.read(&mut buffer).await {}
+                        });
+                        requests
+                    });
+
+                    time::sleep_until(started + Duration::from_micros(record.micros)).await;
+                    if socket.write_all(&record.frame).await.is_err() {
+                        warn!(connection = record.connection, "Replayed connection was closed by the server");
+                        sockets.remove(&record.connection);
+                        continue;
+                    }
+                    replayed += 1;
+                },
+                Direction::Closed => {
+                    time::sleep_until(started + Duration::from_micros(record.micros)).await;
+                    // Reads as the game closing the socket
+                    if let Some(mut socket) = sockets.remove(&record.connection) {
+                        let _ = socket.shutdown().await;
+                    }
+                },
+                Direction::Outbound => {},
+            }
+        }
+
+        for (_, mut socket) in sockets {
+            let _ = socket.shutdown().await;
+        }
+        time::sleep(REPLAY_DRAIN).await;
+        info!(replayed, "Replay finished");
+    }
+
+    async fn handle_request<R: AsyncRead + Unpin>(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, mut reader: R) {
+        let mut first_connection = true;
 
         let mut init_packet = IPacket::<InitPacket>::new();
-        init_packet.packet.max_players = MAX_PLAYERS;
+        init_packet.packet.max_players = server.read().await.settings.server.max_players();
         let mut init_packet_header = IPacket::<PacketHeader>::new();
         init_packet_header.packet.packet_size = init_packet.packet_size as i16;
         init_packet_header.packet.packet_type = PacketType::Init;
         client.read().await.send(&init_packet_header, &init_packet).await;
 
+        let mut frames = FrameDecoder::new();
         loop {
             let mut buffer: [u8; 1024] = [0; 1024];
-            let bytes_result = client.read().await.socket.lock().await
-                .read(&mut buffer)
-                .await;
-            
-            println!("-------------------- NEW PACKET --------------------");
-            
+            let bytes_result = reader.read(&mut buffer).await;
+
             match bytes_result {
+                // The client closed the connection
+                Ok(0) => {
+                    if let Some(error) = frames.leftover() {
+                        debug!("Connection ended part way through a packet");
+                        METRICS.decode_error(error);
+                    }
+                    ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::Closed).await;
+                    return;
+                },
                 Ok(num_bytes) => {
-                    if num_bytes == 0 {
-                        continue;
+                    if capture::enabled() {
+                        let c = client.read().await;
+                        capture::record(Direction::Inbound, c.connection, c.id, &buffer[..num_bytes]);
                     }
 
-                    let incoming_buffer = &buffer[..num_bytes];
-
-                    if num_bytes < 20 {
-                        println!("BYTES DEBUG: {:?}", incoming_buffer);
+                    frames.push(&buffer[..num_bytes]);
+                    loop {
+                        let frame = match frames.next_frame() {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(error) => {
+                                debug!("Packet size doesn't fit in a frame, dropping what was read");
+                                METRICS.decode_error(error);
+                                break;
+                            }
+                        };
+                        if !ServerWrapper::handle_packet(server.clone(), client.clone(), frame, &mut first_connection).await {
+                            return;
+                        }
                     }
-                    let mut packet_header = IPacket::<PacketHeader>::new();
-                    packet_header.deserialize(&incoming_buffer[..packet_header.packet_size]);
-
-                    // Handle UnhandledPackets up here
-                    // They have a packet size of 0 in the header and break later logic
-                    if type_to_packet_map(packet_header.packet.packet_type) == "UnhandledPacket" || type_to_packet_map(packet_header.packet.packet_type) == "CommandPacket" {
-                        println!("Handling {:?}", type_to_packet_map(packet_header.packet.packet_type));
-
-                        // Should it send?
-                        ServerWrapper::broadcast_raw(
-                            server.clone(),
-                            &buffer,
-                            num_bytes,
-                            client.clone()
-                        ).await;
+                },
+                _ => {
+                    ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::ReadError).await;
+                    return;
+                }
+            }
+        }
+    }
 
-                        continue;
-                    }
+    /// Handles one packet from the client, `false` when the connection should end
+    async fn handle_packet(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, frame: Frame, first_connection: &mut bool) -> bool {
+        let Frame { header: mut packet_header, bytes } = frame;
+        let incoming_buffer = &bytes[..];
 
-                    if packet_header.packet_size > 1024 || packet_header.packet.packet_size > 1024 || packet_header.packet.packet_size as usize > 1024 {
-                        println!("Packet Type: {:?}", type_to_packet_map(packet_header.packet.packet_type));
-                        println!("Header Size: {:?}", packet_header.packet_size);
-                        println!("Packet Size: {:?}", packet_header.packet.packet_size);
-                    }
+        METRICS.packet_in(packet_header.packet.packet_type, incoming_buffer.len());
+        if packet_header.packet.packet_type == PacketType::Unknown {
+            METRICS.decode_error(DecodeError::UnknownType);
+        }
 
-                    let packet_data = &incoming_buffer[packet_header.packet_size..];
-
-                    if first_connection {
-                        client.write().await.id = packet_header.packet.id;
-
-                        if type_to_packet_map(packet_header.packet.packet_type) == "ConnectPacket" {
-                            // Handle init to add or replace in client list
-                            let mut connect_packet = IPacket::<ConnectPacket>::new();
-                            connect_packet.deserialize(packet_data);
-                            
-                            match connect_packet.packet.connection_type {
-                                ConnectionTypes::FirstConnection | ConnectionTypes::Reconnecting => {
-                                    println!("First connection / reconnect");
-
-                                    client.write().await.name = connect_packet.packet.client_name;
-                                    client.write().await.connected = true;
-
-                                    println!("Welcome, {:?}", client.read().await.name);
-
-                                    let mut already_connected: bool = false;
-                                    let mut connected_index: usize = 0;
-                                    for i in  0..server.read().await.clients.len() {
-                                        if server.read().await.clients[i].read().await.id == client.read().await.id {
-                                            already_connected = true;
-                                            connected_index = i;
-                                            break;
-                                        }
-                                    }
-
-                                    if already_connected {
-                                        server.write().await.clients[connected_index] = client.clone();
-                                    } else {
-                                        server.write().await.clients.push(client.clone());
-                                    }
-
-                                    first_connection = false;
-
-                                    let mut local_connect_packet = IPacket::<ConnectPacket>::new();
-                                    local_connect_packet.deserialize(packet_data);
-                                    ServerWrapper::broadcast(server.clone(), &mut local_connect_packet, client.clone()).await;
-                                },
-                            }
+        let packet_data = &incoming_buffer[packet_header.packet_size..];
 
-                            if connect_packet.packet.connection_type == ConnectionTypes::FirstConnection {
-                                ServerWrapper::sync_connect(server.clone(), client.clone()).await;
-                            }
-                        }
+        // The game always connects first. Anything else is refused so the allowlist and bans can't be skipped
+        if *first_connection {
+            if packet_header.packet.packet_type != PacketType::Connect {
+                info!(packet = type_to_packet_map(packet_header.packet.packet_type), "Refusing a client that didn't connect first");
+                client.read().await.close();
+                return false;
+            }
+            client.write().await.id = packet_header.packet.id;
+
+            // Handle init to add or replace in client list
+            let mut connect_packet = IPacket::<ConnectPacket>::new();
+            connect_packet.deserialize(packet_data);
+            
+            match connect_packet.packet.connection_type {
+                ConnectionTypes::FirstConnection | ConnectionTypes::Reconnecting => {
+                    Span::current()
+                        .record("uuid", field::display(packet_header.packet.id))
+                        .record("player", connect_packet.packet.client_name.as_str());
+                    {
+                        let mut c = client.write().await;
+                        c.name = connect_packet.packet.client_name.to_string();
+                        c.password = connect_packet.packet.password.to_string();
+                    }
+
+                    let address = client.read().await.address;
+                    if server.read().await.settings.banned_players.bans_client(&packet_header.packet.id, address) {
+                        info!("Refusing banned player");
+                        client.read().await.close();
+                        return false;
                     }
 
-                    // Set the client's Costume
-                    // Typically after connecting the user has to die to get this to show to other clients
-                    if packet_header.packet.packet_type == PacketType::Costume {
-                        let mut costume_packet = IPacket::<CostumePacket>::new();
-                        costume_packet.deserialize(&incoming_buffer[packet_header.packet_size..]);
-                        client.write().await.current_costume = Some(costume_packet.copy());
+                    let allowed = {
+                        let s = server.read().await;
+                        s.settings.allowed_players.allows(&packet_header.packet.id, &connect_packet.packet.client_name, &connect_packet.packet.password, &s.guests)
+                    };
+                    if !allowed {
+                        info!(sent_password = !connect_packet.packet.password.is_empty(), "Refusing player who isn't allowed in");
+                        client.read().await.close();
+                        return false;
                     }
-                    
-                    println!("{:?}", type_to_packet_map(packet_header.packet.packet_type));
-
-                    match packet_header.packet.packet_type {
-                        PacketType::Cap => {
-                            ServerWrapper::packet_builder::<IPacket::<CapPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Init => {
-                            ServerWrapper::packet_builder::<IPacket::<InitPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Player => {
-                            ServerWrapper::packet_builder::<IPacket::<PlayerPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Game => {
-                            ServerWrapper::packet_builder::<IPacket::<GamePacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Tag => {
-                            ServerWrapper::packet_builder::<IPacket::<TagPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Connect => {
-                            ServerWrapper::packet_builder::<IPacket::<ConnectPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Disconnect => {
-                            ServerWrapper::packet_builder::<IPacket::<DisconnectPacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Costume => {
-                            ServerWrapper::packet_builder::<IPacket::<CostumePacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Shine => {
-                            ServerWrapper::packet_builder::<IPacket::<ShinePacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::Capture => {
-                            ServerWrapper::packet_builder::<IPacket::<CapturePacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        PacketType::ChangeStage => {
-                            ServerWrapper::packet_builder::<IPacket::<ChangeStagePacket>>(
-                                server.clone(),
-                                client.clone(),
-                                incoming_buffer.clone(),
-                                &mut packet_header
-                            ).await;
-                        },
-                        _ => {
-                            ServerWrapper::broadcast_raw(
-                                server.clone(),
-                                &buffer,
-                                num_bytes,
-                                client.clone()
-                            ).await;
-                        },
+
+                    // Reconnecting players keep their place
+                    let full = {
+                        let s = server.read().await;
+                        let connected = s.players.connected();
+                        connected.len() >= s.settings.server.max_players() as usize &&
+                            !connected.iter().any(|player| player.id == packet_header.packet.id)
+                    };
+                    if full {
+                        info!("Refusing player, the server is full");
+                        client.read().await.close();
+                        return false;
                     }
+
+                    info!("Welcome");
+
+                    // Replaces an earlier connection with the same UUID
+                    let players = server.read().await.players.clone();
+                    players.join(client.clone()).await;
+
+                    *first_connection = false;
+
+                    let mut local_connect_packet = IPacket::<ConnectPacket>::new();
+                    local_connect_packet.deserialize(packet_data);
+                    ServerWrapper::broadcast(server.clone(), &mut local_connect_packet, client.clone()).await;
                 },
-                _ => {
-                    client.write().await.connected = false;
-                    return;
-                }
+            }
+
+            if connect_packet.packet.connection_type == ConnectionTypes::FirstConnection {
+                ServerWrapper::sync_connect(server.clone(), client.clone()).await;
             }
         }
-    }
 
+        // Kicked, replaced by a newer connection with the same UUID, or banned since joining
+        if !ServerWrapper::may_relay(&server, &client).await {
+            return false;
+        }
+
+        // Handle UnhandledPackets up here
+        // They have a packet size of 0 in the header and break later logic
+        if type_to_packet_map(packet_header.packet.packet_type) == "UnhandledPacket" || type_to_packet_map(packet_header.packet.packet_type) == "CommandPacket" {
+            trace!(packet = type_to_packet_map(packet_header.packet.packet_type), "Relaying unhandled packet");
 
+            // Should it send?
+            ServerWrapper::broadcast_raw(
+                server.clone(),
+                incoming_buffer,
+                incoming_buffer.len(),
+                client.clone()
+            ).await;
 
-    pub async fn packet_builder<T: IPacketTrait>(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, incoming_buffer: &[u8], packet_header: &mut IPacket<PacketHeader>) {
-        println!("packet_builder");
+            return true;
+        }
+
+        // Set the client's Costume
+        // Typically after connecting the user has to die to get this to show to other clients
+        if packet_header.packet.packet_type == PacketType::Costume {
+            let mut costume_packet = IPacket::<CostumePacket>::new();
+            costume_packet.deserialize(&incoming_buffer[packet_header.packet_size..]);
+            client.write().await.current_costume = Some(costume_packet.copy());
+        }
+
+        trace!(packet = type_to_packet_map(packet_header.packet.packet_type), size = packet_header.packet.packet_size, "Received");
+
+        match packet_header.packet.packet_type {
+            PacketType::Cap => {
+                ServerWrapper::packet_builder::<IPacket::<CapPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Init => {
+                ServerWrapper::packet_builder::<IPacket::<InitPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Player => {
+                ServerWrapper::packet_builder::<IPacket::<PlayerPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Game => {
+                ServerWrapper::packet_builder::<IPacket::<GamePacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Tag => {
+                ServerWrapper::packet_builder::<IPacket::<TagPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Connect => {
+                ServerWrapper::packet_builder::<IPacket::<ConnectPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Disconnect => {
+                ServerWrapper::packet_builder::<IPacket::<DisconnectPacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Costume => {
+                ServerWrapper::packet_builder::<IPacket::<CostumePacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Shine => {
+                ServerWrapper::packet_builder::<IPacket::<ShinePacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::Capture => {
+                ServerWrapper::packet_builder::<IPacket::<CapturePacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            PacketType::ChangeStage => {
+                ServerWrapper::packet_builder::<IPacket::<ChangeStagePacket>>(
+                    server.clone(),
+                    client.clone(),
+                    incoming_buffer,
+                    &mut packet_header
+                ).await;
+            },
+            _ => {
+                ServerWrapper::broadcast_raw(
+                    server.clone(),
+                    incoming_buffer,
+                    incoming_buffer.len(),
+                    client.clone()
+                ).await;
+            },
+        }
+
+        true
+    }
+
+    /// Whether the client's packets can still be passed on, a banned player is kicked
+    async fn may_relay(server: &Arc<RwLock<Server>>, client: &Arc<RwLock<Client>>) -> bool {
+        let (id, address, connected) = {
+            let c = client.read().await;
+            (c.id, c.address, c.connected.load(Ordering::Acquire))
+        };
+        if !connected {
+            return false;
+        }
+        let banned = server.read().await.settings.banned_players.bans_client(&id, address);
+        if banned {
+            ServerWrapper::kick(server.clone(), client.clone()).await;
+        }
+        !banned
+    }
+
+    pub(crate) async fn packet_builder<T: IPacketTrait>(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, incoming_buffer: &[u8], packet_header: &mut IPacket<PacketHeader>) {
         let mut packet_serialized = T::new();
         packet_serialized.deserialize(&incoming_buffer[PACKET_HEADER_SIZE..]);
-        let will_send = ServerWrapper::packet_handler(server.clone(), client.clone(), packet_header, packet_serialized).await;
 
-        if will_send {
-            // TODO: Implement Copy on packets so I don't need to copy and paste this so often
-            let mut packet_serialized = T::new();
-            packet_serialized.deserialize(&incoming_buffer[PACKET_HEADER_SIZE..]);
-            ServerWrapper::broadcast(server.clone(), &mut packet_serialized, client.clone()).await;
+        let mut inbound = HookPacket::new(packet_header, &packet_serialized);
+        let packets = {
+            let sender = PlayerView::of(&*client.read().await);
+            inbound.header.packet.id = sender.id;
+
+            let s = server.read().await;
+            let packets = s.hooks.receive(&s.settings, &sender, inbound);
+            if packets.is_empty() {
+                debug!(packet = packet_serialized.get_name(), sender = %sender.name, "A packet hook dropped the packet");
+            }
+            packets
+        };
+
+        for inbound in packets {
+            // Packets a hook added of another type are only relayed
+            if inbound.packet_type() != packet_header.packet.packet_type {
+                ServerWrapper::relay(server.clone(), client.clone(), inbound).await;
+                continue;
+            }
+
+            let packet: T = inbound.decode();
+            let will_send = ServerWrapper::packet_handler(server.clone(), client.clone(), packet).await;
+
+            if will_send {
+                ServerWrapper::relay(server.clone(), client.clone(), inbound).await;
+            }
         }
     }
 
-    async fn packet_handler<T: IPacketTrait>(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, packet_header: &mut IPacket<PacketHeader>, packet: T) -> bool
-    where T: IPacketTrait
+    async fn packet_handler<T: IPacketTrait>(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, packet: T) -> bool
     {
-        println!("packet_handler");
         match packet.get_name() {
             "GamePacket" => {
                 let mut copied_packet = IPacket::<GamePacket>::new();
@@ -358,21 +619,53 @@ impl ServerWrapper {
                 copied_packet = IPacket::<GamePacket>::new();
                 copied_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
 
-                match copied_packet.packet.stage.as_str() {
-                    "CapWorldHomeStage" => {
-                        client.write().await.metadata.speedrun = true;
+                if !stages::is_plausible_stage(&copied_packet.packet.stage) {
+                    warn!(stage = %copied_packet.packet.stage, "Unknown stage");
+                } else {
+                    info!(
+                        stage = stages::friendly_name(&copied_packet.packet.stage),
+                        scenario = copied_packet.packet.scenario_num,
+                        "Changed stage"
+                    );
+                }
+
+                let kingdom = stages::kingdom_for_stage(&copied_packet.packet.stage);
+                if kingdom.is_some() {
+                    client.write().await.metadata.kingdom = kingdom;
+                }
+                // Client fields are read first, the server lock is never awaited while holding a client's
+                let (id, current_kingdom) = {
+                    let c = client.read().await;
+                    (c.id, c.metadata.kingdom)
+                };
+                let relayed_scenario = server.read().await.settings.scenario.relayed_scenario(
+                    current_kingdom,
+                    copied_packet.packet.scenario_num
+                );
+                client.write().await.metadata.relayed_scenario = relayed_scenario;
 
-                        // Shine Sync
-                        // https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L128
+                server.read().await.events.publish(ServerEvent::StageChanged {
+                    id,
+                    stage: copied_packet.packet.stage.to_string(),
+                    scenario: copied_packet.packet.scenario_num,
+                    is_2d: copied_packet.packet.is_2d,
+                    kingdom,
+                });
 
-                        // println!("Cap kingdom, do not sync shines");
+                match kingdom {
+                    Some(Kingdom::Cap) => {
+                        // A new save, don't sync shines until they reach Cascade
+                        // https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L128
+                        let mut c = client.write().await;
+                        c.metadata.speedrun = true;
+                        c.metadata.shine_sync.clear();
                     },
-                    "WaterfallWorldHomeStage" => {
+                    Some(Kingdom::Cascade) => {
                         let was_speedrun = client.read().await.metadata.speedrun;
                         client.write().await.metadata.speedrun = false;
-                        if was_speedrun {
-                            // Shine Sync with delay
+                        if was_speedrun && server.read().await.settings.shine.enabled {
                             // https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L135-L140
+                            tokio::spawn(ShineSync::sync_later(server.clone(), client.clone(), SPEEDRUN_SYNC_DELAY));
                         }
                     },
                     _ => {
@@ -380,29 +673,17 @@ impl ServerWrapper {
                     }
                 }
 
-                if server.read().await.settings.scenario.merge_enabled {
-                    ServerWrapper::broadcast_replace(
-                        server.clone(),
-                        packet_header,
-                        copied_packet,
-                        client.clone(),
-                        |_server: Arc<RwLock<Server>>, from: Arc<RwLock<Client>>, to: Arc<RwLock<Client>>, header: &mut IPacket<PacketHeader>, p: &mut IPacket<GamePacket>| {
-                            println!("anonymous 2");
-                            let mut copied_packet_header = IPacket::<PacketHeader>::new();
-                            copied_packet_header.deserialize(&header.serialize()[..header.get_size().to_owned()]);
-                            let mut copied_packet = IPacket::<GamePacket>::new();
-                            copied_packet.deserialize(&p.serialize()[..p.get_size().to_owned()]);
-                            
-                            return async move {
-                                copied_packet.packet.scenario_num = from.read().await.metadata.scenario;
-
-                                let result = to.read().await.send(&copied_packet_header, &copied_packet).await;
-                                if !result {
-                                    to.write().await.connected = false;
-                                }
-                            };
-                        }
-                    ).await;
+            },
+            "ChangeStagePacket" => {
+                let mut copied_packet = IPacket::<ChangeStagePacket>::new();
+                copied_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+
+                if !stages::is_plausible_stage(&copied_packet.packet.stage) || !stages::is_valid_scenario(copied_packet.packet.scenario) {
+                    warn!(
+                        stage = %copied_packet.packet.stage,
+                        scenario = copied_packet.packet.scenario,
+                        "Dropping ChangeStagePacket with an unknown stage"
+                    );
                     return false;
                 }
             },
@@ -411,7 +692,19 @@ impl ServerWrapper {
                 copied_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
 
                 if (copied_packet.packet.update_type as u8 & TagUpdate::State as u8) != 0 {
-                    client.write().await.metadata.seeking = copied_packet.packet.is_it;
+                    let is_it = copied_packet.packet.is_it;
+                    let (id, was_it) = {
+                        let mut c = client.write().await;
+                        let was_it = c.metadata.seeking;
+                        c.metadata.seeking = is_it;
+                        (c.id, was_it)
+                    };
+
+                    let events = &server.read().await.events;
+                    events.publish(ServerEvent::TagStateChanged { id, is_it });
+                    if is_it && !was_it {
+                        events.publish(ServerEvent::Caught { id });
+                    }
                 }
                 if (copied_packet.packet.update_type as u8 & TagUpdate::Time as u8) != 0 {
                     client.write().await.metadata.time = Time {
@@ -422,159 +715,168 @@ impl ServerWrapper {
                 }
             },
             "CostumePacket" => {
+                let mut costume_packet = IPacket::<CostumePacket>::new();
+                costume_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+
                 // Shine sync
                 // https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L165
-                client.write().await.metadata.loaded_save = true;
+                let id = {
+                    let mut c = client.write().await;
+                    c.metadata.loaded_save = true;
+                    c.id
+                };
+                server.read().await.events.publish(ServerEvent::CostumeChanged {
+                    id,
+                    body: costume_packet.packet.body_name,
+                    cap: costume_packet.packet.cap_name,
+                });
             },
             "ShinePacket" => {
-                if client.write().await.metadata.loaded_save {
+                let (id, loaded_save) = {
+                    let c = client.read().await;
+                    (c.id, c.metadata.loaded_save)
+                };
+                if loaded_save {
+                    let mut shine_packet = IPacket::<ShinePacket>::new();
+                    shine_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+
                     // Shine sync
                     // https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L169-L178
+                    server.read().await.events.publish(ServerEvent::ShineCollected {
+                        id,
+                        shine_id: shine_packet.packet.shine_id,
+                    });
+                }
+
+                // Shine sync sends shines to everyone instead
+                if server.read().await.settings.shine.enabled {
+                    return false;
+                }
+            },
+            "CapturePacket" => {
+                let mut capture_packet = IPacket::<CapturePacket>::new();
+                capture_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+
+                let id = client.read().await.id;
+                server.read().await.events.publish(ServerEvent::CaptureChanged {
+                    id,
+                    model: capture_packet.packet.module_name,
+                });
+            },
+            "CapPacket" => {
+                let mut cap_packet = IPacket::<CapPacket>::new();
+                cap_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+
+                // The tick loop relays it on its next tick
+                if server.read().await.settings.tick.enabled {
+                    client.write().await.metadata.pending_cap = Some(cap_packet);
+                    return false;
                 }
             },
             "PlayerPacket" => {
                 let mut player_packet = IPacket::<PlayerPacket>::new();
                 player_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
 
-                let settings = &server.read().await.settings;
-                if settings.flip.enabled &&
-                    (settings.flip.pov == FlipOptions::BothOption || settings.flip.pov == FlipOptions::SelfOption) &&
-                    settings.flip.players.contains(&client.read().await.id)
-                {
-                    player_packet.packet.position = Vector3::<f32>::new(0.0, 1.0, 0.0) * ServerWrapper::mario_size(client.read().await.metadata.is_2d);
-                    player_packet.packet.rotation *= Quaternion::<f32>::create_from_rotation_matrix_x() * Quaternion::<f32>::create_from_rotation_matrix_y();
-                    ServerWrapper::broadcast::<IPacket<PlayerPacket>>(server.clone(), &mut player_packet, client.clone()).await;
-                } else if settings.flip.enabled &&
-                    (settings.flip.pov == FlipOptions::BothOption || settings.flip.pov == FlipOptions::OthersOption) &&
-                    settings.flip.players.contains(&client.read().await.id)
-                {
-                    player_packet.packet.position = Vector3::<f32>::new(0.0, 1.0, 0.0) * ServerWrapper::mario_size(client.read().await.metadata.is_2d);
-                    player_packet.packet.rotation *= Quaternion::<f32>::create_from_rotation_matrix_x() * Quaternion::<f32>::create_from_rotation_matrix_y();
-                    ServerWrapper::broadcast_replace(
-                        server.clone(),
-                        packet_header,
-                        player_packet,
-                        client.clone(),
-                        |server: Arc<RwLock<Server>>, from: Arc<RwLock<Client>>, to: Arc<RwLock<Client>>, header: &mut IPacket<PacketHeader>, p: &mut IPacket<PlayerPacket>| {
-                            println!("anonymous 1");
-                            let mut copied_packet_header = IPacket::<PacketHeader>::new();
-                            copied_packet_header.deserialize(&header.serialize()[..header.get_size().to_owned()]);
-                            let mut copied_packet = IPacket::<PlayerPacket>::new();
-                            copied_packet.deserialize(&p.serialize()[..p.get_size().to_owned()]);
-
-                            return async move {
-                                if server.read().await.settings.flip.players.contains(&to.read().await.id) {
-                                    copied_packet.packet.position = Vector3::<f32>::new(0.0, 1.0, 0.0) * ServerWrapper::mario_size(from.read().await.metadata.is_2d);
-                                    copied_packet.packet.rotation *= Quaternion::<f32>::create_from_rotation_matrix_x() * Quaternion::<f32>::create_from_rotation_matrix_y();
-                                }
-
-                                let result = to.read().await.send(&copied_packet_header, &copied_packet).await;
-                                if !result {
-                                    to.write().await.connected = false;
-                                }
-                            };
-                        }
-                    ).await;
+                let (smoothing, batching) = {
+                    let settings = &server.read().await.settings;
+                    (settings.smoothing.enabled, settings.tick.enabled)
+                };
+
+
+                // The smoother or tick loop relays it on its next tick
+                if smoothing {
+                    client.write().await.metadata.motion.push(&player_packet.packet, Instant::now());
+                    return false;
+                }
+                if batching {
+                    client.write().await.metadata.pending_player = Some(player_packet);
                     return false;
                 }
             },
             _ => {
-                // println!("Unsupported Packet?");
+                // trace!("Unsupported Packet?");
             }
         }
 
-        return true;
+        true
     }
 
-    pub async fn broadcast_replace<T: IPacketTrait, Fut>(
-        server: Arc<RwLock<Server>>,
-        header: &mut IPacket<PacketHeader>,
-        packet: T,
-        client: Arc<RwLock<Client>>,
-        packet_replacer: fn(
-            server: Arc<RwLock<Server>>,
-            from: Arc<RwLock<Client>>,
-            to: Arc<RwLock<Client>>,
-            header: &mut IPacket<PacketHeader>,
-            p: &mut T
-        ) -> Fut
-    )
-    where T: IPacketTrait,
-            Fut: Future<Output = ()>
-    {
-        println!("broadcast_replace");
-        let clients_iterable = &server.read().await.clients;
-        for c_index in 0..clients_iterable.len() {
-            let local_client = &clients_iterable[c_index];
-            let local_server = server.clone();
-            let mut packet_copy = T::new();
-            packet_copy.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
+    /// Sends the packet to every other connected player, passing each copy through the packet hooks
+    pub(crate) async fn relay(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, packet: HookPacket) {
+        let started = Instant::now();
+        let sender = PlayerView::of(&*client.read().await);
+        let players = server.read().await.players.clone();
+        let others = players.others(&sender.id);
+
+        // Each client guard is dropped before the server is locked, one is never awaited while holding the other
+        let mut receivers: Vec<(PlayerEntry, PlayerView)> = Vec::with_capacity(others.len());
+        for player in others {
+            let view = PlayerView::of(&*player.client.read().await);
+            receivers.push((player, view));
+        }
 
-            if local_client.read().await.connected && client.read().await.id != local_client.read().await.id {
-                packet_replacer(local_server, client.clone(), local_client.clone(), header, &mut packet_copy).await;
+        let mut frames: Vec<(PlayerEntry, Vec<u8>)> = Vec::with_capacity(receivers.len());
+        {
+            let s = server.read().await;
+            for (player, receiver) in receivers {
+                let frame: Vec<u8> = s.hooks.relay(&s.settings, &sender, &receiver, packet.copy())
+                    .iter()
+                    .flat_map(|packet| packet.frame())
+                    .collect();
+                if !frame.is_empty() {
+                    frames.push((player, frame));
+                }
             }
         }
+
+        for (player, frame) in frames {
+            if !player.send_frame(&frame) {
+                info!(player = %player.name, "Disconnected, the send queue is full or closed");
+                players.disconnect(&player.id, &player.connected, DisconnectReason::SendFailed);
+            }
+        }
+        METRICS.broadcast(started.elapsed());
     }
 
-    pub async fn sync_connect(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>)
+    pub(crate) async fn sync_connect(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>)
     {
-        println!("sync_connect");
-
-        println!("Async To Player: {:?}", client.read().await.name);
-
         let client_id = client.read().await.id;
-        let all_players = &server.read().await.clients;
-        let mut other_players: Vec<Arc<RwLock<Client>>> = Vec::new();
-        let num_players = all_players.len();
-        println!("Num Players: {:?}", num_players);
-        for i in 0..num_players {
-            let player = all_players[i].clone();
-            println!("Testing player if it is the client {:?}, {:?}", i, player.read().await.name);
-            if player.read().await.id != client_id {
-                println!("Testing player if it is connected {:?}, {:?}", i, player.read().await.name);
-                if player.read().await.connected {
-                    println!("Adding player {:?}, {:?}", i, player.read().await.name);
-                    other_players.push(player.clone());
-                }
-            }
-        }
-        for c in other_players {
-            println!("Sync From Player: {:?}", c.read().await.name);
+        let (players, max_players) = {
+            let s = server.read().await;
+            (s.players.clone(), s.settings.server.max_players())
+        };
+        for player in players.others(&client_id) {
+            trace!(from = %player.name, "Syncing connected player");
 
             let mut connect_packet = IPacket::<ConnectPacket>::new();
-            connect_packet.packet.client_name = c.read().await.name.as_str().to_string();
+            connect_packet.packet.client_name = player.name.to_string();
             connect_packet.packet.connection_type = ConnectionTypes::FirstConnection;
-            connect_packet.packet.max_players = MAX_PLAYERS;
+            connect_packet.packet.max_players = max_players;
 
-            let packet_type_name = connect_packet.get_name().clone();
+            let packet_type_name = connect_packet.get_name();
             let packet_size_usize = connect_packet.get_size().to_owned();
-            let packet_type = packet_to_type_map(&packet_type_name);
+            let packet_type = packet_to_type_map(packet_type_name);
             let packet_size = packet_size_usize as i16;
             
             let mut packet_header = IPacket::<PacketHeader>::new();
-            packet_header.packet.id = c.read().await.id;
+            packet_header.packet.id = player.id;
             packet_header.packet.packet_type = packet_type;
             packet_header.packet.packet_size = packet_size;
 
             let result = client.read().await.send(&packet_header, &connect_packet).await;
             if !result {
-                println!("{:?} disconnected due to client sync", client.read().await.name);
-                client.write().await.connected = false;
+                info!("Disconnected while syncing connected players");
+                ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::SendFailed).await;
+                break;
             }
         }
-        println!("Sent sync packets");
     }
 
-    pub async fn broadcast<T: IPacketTrait>(server: Arc<RwLock<Server>>, packet: &mut T, client: Arc<RwLock<Client>>)
-    where T: IPacketTrait
+    pub(crate) async fn broadcast<T: IPacketTrait>(server: Arc<RwLock<Server>>, packet: &mut T, client: Arc<RwLock<Client>>)
     {
-        println!("broadcast");
-        let mut copied_packet = T::new();
-        copied_packet.deserialize(&packet.serialize()[..packet.get_size().to_owned()]);
-
-        let packet_type_name = packet.get_name().clone();
+        let packet_type_name = packet.get_name();
         let packet_size_usize = packet.get_size().to_owned();
-        let packet_type = packet_to_type_map(&packet_type_name);
+        let packet_type = packet_to_type_map(packet_type_name);
         let packet_size = packet_size_usize as i16;
         
         let mut packet_header = IPacket::<PacketHeader>::new();
@@ -582,73 +884,156 @@ impl ServerWrapper {
         packet_header.packet.packet_type = packet_type;
         packet_header.packet.packet_size = packet_size;
 
-        println!("From Player: {:?}", client.read().await.name);
+        let frame = encode_frame(&packet_header, packet);
+        ServerWrapper::broadcast_frame(server, &frame, packet_header.packet.id).await;
+    }
 
+    pub(crate) async fn broadcast_raw(server: Arc<RwLock<Server>>, data: &[u8], size: usize, client: Arc<RwLock<Client>>)
+    {
         let client_id = client.read().await.id;
-        let all_players = &server.read().await.clients;
-        let mut other_players: Vec<Arc<RwLock<Client>>> = Vec::new();
-        let num_players = all_players.len();
-        println!("Num Players: {:?}", num_players);
-        for i in 0..num_players {
-            let player = all_players[i].clone();
-            println!("Testing player if it is the client {:?}, {:?}", i, player.read().await.name);
-            if player.read().await.id != client_id {
-                println!("Testing player if it is connected {:?}, {:?}", i, player.read().await.name);
-                if player.read().await.connected {
-                    println!("Adding player {:?}, {:?}", i, player.read().await.name);
-                    other_players.push(player.clone());
-                }
-            }
-        }
-        for c in other_players {
-            println!("To Player: {:?}", c.read().await.name);
-            let result = c.read().await.send(&packet_header, &copied_packet).await;
-            if !result {
-                println!("{:?} disconnected due to client", c.read().await.name);
-                c.write().await.connected = false;
+        ServerWrapper::broadcast_frame(server, &data[..size], client_id).await;
+    }
+
+    /// Queues an encoded frame for every connected player except the sender
+    pub(crate) async fn broadcast_frame(server: Arc<RwLock<Server>>, frame: &[u8], from: Uuid) {
+        let started = Instant::now();
+        let players = server.read().await.players.clone();
+        for player in players.others(&from) {
+            if !player.send_frame(frame) {
+                info!(player = %player.name, "Disconnected, the send queue is full or closed");
+                players.disconnect(&player.id, &player.connected, DisconnectReason::SendFailed);
             }
         }
-        println!("Sent packets");
+        METRICS.broadcast(started.elapsed());
     }
 
-    pub async fn broadcast_raw(server: Arc<RwLock<Server>>, data: &[u8], size: usize, client: Arc<RwLock<Client>>)
-    {
-        println!("broadcast_raw");
+    /// Marks the client as gone, a newer connection with the same UUID stays connected
+    pub(crate) async fn disconnect(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, reason: DisconnectReason) {
+        let (id, connected) = {
+            let c = client.read().await;
+            (c.id, c.connected.clone())
+        };
+        server.read().await.players.disconnect(&id, &connected, reason);
+    }
 
-        println!("From Player: {:?}", client.read().await.name);
+    /// Tells everyone the other players left, closes every socket once what's queued for it is written
+    /// and saves state. Runs after `start` has stopped, so nobody new can join
+    pub(crate) async fn shutdown(server: Arc<RwLock<Server>>) {
+        let (players, drain_timeout) = {
+            let s = server.read().await;
+            (s.players.clone(), Duration::from_millis(s.settings.shutdown.drain_timeout_ms))
+        };
+        let connected = players.connected();
+        info!(players = connected.len(), "Shutting down");
 
-        let client_id = client.read().await.id;
-        let all_players = &server.read().await.clients;
-        let mut other_players: Vec<Arc<RwLock<Client>>> = Vec::new();
-        let num_players = all_players.len();
-        println!("Num Players: {:?}", num_players);
-        for i in 0..num_players {
-            let player = all_players[i].clone();
-            println!("Testing player if it is the client {:?}, {:?}", i, player.read().await.name);
-            if player.read().await.id != client_id {
-                println!("Testing player if it is connected {:?}, {:?}", i, player.read().await.name);
-                if player.read().await.connected {
-                    println!("Adding player {:?}, {:?}", i, player.read().await.name);
-                    other_players.push(player.clone());
-                }
+        let disconnect_packet = IPacket::<DisconnectPacket>::new();
+        for player in &connected {
+            let mut disconnect_header = IPacket::<PacketHeader>::new();
+            disconnect_header.packet.id = player.id;
+            disconnect_header.packet.packet_type = PacketType::Disconnect;
+            disconnect_header.packet.packet_size = disconnect_packet.get_size().to_owned() as i16;
+            let frame = encode_frame(&disconnect_header, &disconnect_packet);
+            for other in connected.iter().filter(|other| other.id != player.id) {
+                other.send_frame(&frame);
             }
         }
-        for c in other_players {
-            println!("To Player: {:?}", c.read().await.name);
-            let result = c.read().await.send_raw_data(data, size).await;
-            if !result {
-                println!("{:?} disconnected due to client", c.read().await.name);
-                c.write().await.connected = false;
+        // Disconnecting closes the socket once what's queued for it is written
+        for player in &connected {
+            players.disconnect(&player.id, &player.connected, DisconnectReason::Shutdown);
+        }
+
+        // A writer task drops its end of the queue once the socket is closed
+        let drained = time::timeout(drain_timeout, async {
+            for player in &connected {
+                player.outgoing.closed().await;
             }
+        }).await;
+        if drained.is_err() {
+            warn!(timeout_ms = drain_timeout.as_millis() as u64, "Gave up waiting for packets to be written");
+        }
+
+        persist::save(server).await;
+    }
+
+    /// Connected clients matching a name (case insensitive) or UUID, `*` matches everyone
+    pub async fn find_clients(server: Arc<RwLock<Server>>, selector: &str) -> Vec<Arc<RwLock<Client>>> {
+        let players = server.read().await.players.clone();
+        players.connected()
+            .into_iter()
+            .filter(|player| selector == "*" || player.name.eq_ignore_ascii_case(selector) || player.id.to_string() == selector)
+            .map(|player| player.client)
+            .collect()
+    }
+
+    /// Sends a ChangeStagePacket to the client, stage and scenario should come from `stages::resolve_warp_target`
+    pub async fn warp(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, stage: &str, id: &str, scenario: i8) -> bool {
+        let mut change_stage_packet = IPacket::<ChangeStagePacket>::new();
+        change_stage_packet.packet.stage = stage.to_string();
+        change_stage_packet.packet.id = id.to_string();
+        change_stage_packet.packet.scenario = scenario;
+        change_stage_packet.packet.sub_scenario_type = 0;
+
+        let mut packet_header = IPacket::<PacketHeader>::new();
+        packet_header.packet.id = client.read().await.id;
+        packet_header.packet.packet_type = PacketType::ChangeStage;
+        packet_header.packet.packet_size = change_stage_packet.get_size().to_owned() as i16;
+
+        let name = client.read().await.name.to_string();
+        info!(player = %name, stage = stages::friendly_name(stage), scenario, "Warping");
+        let result = client.read().await.send(&packet_header, &change_stage_packet).await;
+        if !result {
+            ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::SendFailed).await;
+        }
+        result
+    }
+
+    /// Sets whether the client is a seeker, like the game does when a hider gets caught
+    pub async fn send_tag_state(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, is_it: bool) -> bool {
+        let mut tag_packet = IPacket::<TagPacket>::new();
+        tag_packet.packet.update_type = TagUpdate::State;
+        tag_packet.packet.is_it = is_it;
+
+        let mut packet_header = IPacket::<PacketHeader>::new();
+        packet_header.packet.id = client.read().await.id;
+        packet_header.packet.packet_type = PacketType::Tag;
+        packet_header.packet.packet_size = tag_packet.get_size().to_owned() as i16;
+
+        let result = client.read().await.send(&packet_header, &tag_packet).await;
+        if result {
+            client.write().await.metadata.seeking = is_it;
+        } else {
+            ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::SendFailed).await;
         }
-        println!("Sent packets");
+        result
     }
 
-    fn mario_size(is_2d: bool) -> f32 {
-        println!("mario_size");
-        if is_2d {
-            return 180.0;
+    /// Kicks connected players who are banned or no longer let in by `allowed_players`, returns their names.
+    /// Run whenever either changes
+    pub async fn enforce_access(server: Arc<RwLock<Server>>) -> Vec<String> {
+        let mut kicked = Vec::new();
+        for c in ServerWrapper::find_clients(server.clone(), "*").await {
+            let (id, name, address, password) = {
+                let client = c.read().await;
+                (client.id, client.name.to_string(), client.address, client.password.to_string())
+            };
+            let refused = {
+                let s = server.read().await;
+                s.settings.banned_players.bans_client(&id, address)
+                    || !s.settings.allowed_players.allows(&id, &name, &password, &s.guests)
+            };
+            if refused {
+                ServerWrapper::kick(server.clone(), c).await;
+                kicked.push(name);
+            }
         }
-        return 160.0;
+        kicked
+    }
+
+    /// Disconnects the client and closes its socket, the game will try to reconnect
+    pub async fn kick(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>) {
+        let name = client.read().await.name.to_string();
+        info!(player = %name, "Kicking");
+        ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::Kicked).await;
+        client.read().await.close();
     }
 }
\ No newline at end of file
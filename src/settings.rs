@@ -1,18 +1,95 @@
+//! Server configuration, the binary runs with [`Settings::defaults`]
+use std::{
+    env,
+    fmt,
+    fs,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr
+};
+use serde::{Serialize, Deserialize};
 use uuid::Uuid;
+use crate::stages::Kingdom;
 
 pub const MAX_PLAYERS: u16 = 8;
 
+// Read by the binary on startup and again whenever it changes, see reload.rs
+pub const SETTINGS_FILE: &str = "./settings.json";
+
+/// One table per feature, each with its own defaults. Tables missing from a settings file get their defaults
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub server: ServerTable,
     pub scenario: ScenarioTable,
     pub banned_players: BannedPlayers,
+    pub allowed_players: AllowedPlayers,
     pub flip: FlipTable,
+    pub transforms: TransformTable,
     pub discord: DiscordTable,
+    pub smoothing: SmoothingTable,
+    pub tick: TickTable,
+    pub rate_limit: RateLimitTable,
     pub shine: ShineTable,
-    pub persist_shines: PersistShinesTable,
+    pub persist_shines: PersistTable,
+    pub persist_bans: PersistTable,
+    pub persist_stats: PersistTable,
+    pub shutdown: ShutdownTable,
+    pub admin: AdminTable,
+    pub metrics: MetricsTable,
+    pub logging: LoggingTable,
+    pub capture: CaptureTable,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::defaults()
+    }
 }
 
 impl Settings {
+    /// Reads the settings file, creating it with the defaults if it doesn't exist.
+    /// The overrides are applied after reading and never written to the file, then the result is validated
+    pub fn load(path: impl AsRef<Path>, overrides: &SettingsOverrides) -> io::Result<Settings> {
+        let path = path.as_ref();
+        if !path.exists() {
+            fs::write(path, serde_json::to_string_pretty(&Settings::defaults())?)?;
+        }
+        Settings::read(path, overrides)
+    }
+
+    /// Reads a settings file, applies the overrides and validates the result
+    pub fn read(path: impl AsRef<Path>, overrides: &SettingsOverrides) -> io::Result<Settings> {
+        let path = path.as_ref();
+        let invalid = |error: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), error));
+        let contents = fs::read(path).map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+        let mut settings: Settings = serde_json::from_slice(&contents).map_err(|error| invalid(error.to_string()))?;
+        overrides.apply(&mut settings);
+        settings.validate().map_err(invalid)?;
+        Ok(settings)
+    }
+
+    /// Catches what deserializing can't, like addresses that don't parse
+    pub fn validate(&self) -> Result<(), String> {
+        self.server.address.parse::<IpAddr>()
+            .map_err(|_| format!("server.address {:?} isn't an IP address", self.server.address))?;
+        for (name, address) in [("admin.address", &self.admin.address), ("metrics.address", &self.metrics.address)] {
+            address.parse::<SocketAddr>()
+                .map_err(|_| format!("{} {:?} isn't an IP address and port", name, address))?;
+        }
+        if let Some(address) = self.banned_players.ip_addresses.iter().find(|address| address.parse::<IpAddr>().is_err()) {
+            return Err(format!("banned_players.ip_addresses has {:?}, which isn't an IP address", address));
+        }
+        if self.allowed_players.password.as_ref().is_some_and(|password| password.len() > 32) {
+            return Err("allowed_players.password can't be longer than 32 bytes, the most the ConnectPacket carries".to_string());
+        }
+        if self.rate_limit.enabled && self.rate_limit.packets_per_second == 0 {
+            return Err("rate_limit.packets_per_second must be above 0".to_string());
+        }
+        Ok(())
+    }
+
     pub fn defaults() -> Self {
         Settings {
             server: ServerTable {
@@ -21,64 +98,373 @@ impl Settings {
                 max_players: 0
             },
             scenario: ScenarioTable {
-                merge_enabled: false
+                merge_enabled: false,
+                merge_kingdoms: Vec::new(),
+                merge_groups: Vec::new(),
+                fixed: Vec::new(),
             },
             banned_players: BannedPlayers {
                 enabled: false,
                 players: Vec::new(),
                 ip_addresses: Vec::new()
             },
+            allowed_players: AllowedPlayers {
+                enabled: false,
+                players: Vec::new(),
+                names: Vec::new(),
+                password: None,
+            },
             flip: FlipTable {
                 enabled: false,
                 players: Vec::new(),
                 pov: FlipOptions::BothOption
             },
+            transforms: TransformTable {
+                pairs: Vec::new()
+            },
             discord: DiscordTable {
                 token: None,
                 prefix: "$".to_string(),
                 command_channel: None,
                 log_channel: None,
             },
+            smoothing: SmoothingTable {
+                enabled: false,
+                tick_rate: 60,
+                interpolation_delay_ms: 50,
+                max_extrapolation_ms: 100,
+            },
+            tick: TickTable {
+                enabled: false,
+                tick_rate: 30,
+            },
+            rate_limit: RateLimitTable {
+                enabled: false,
+                packets_per_second: 240,
+            },
             shine: ShineTable {
                 enabled: true
             },
-            persist_shines: PersistShinesTable {
-                enabled: true,
+            persist_shines: PersistTable {
+                enabled: false,
                 file_name: "./moons.json".to_string()
+            },
+            persist_bans: PersistTable {
+                enabled: false,
+                file_name: "./bans.json".to_string()
+            },
+            persist_stats: PersistTable {
+                enabled: false,
+                file_name: "./stats.prom".to_string()
+            },
+            shutdown: ShutdownTable {
+                drain_timeout_ms: 5000
+            },
+            admin: AdminTable {
+                enabled: false,
+                address: "127.0.0.1:1028".to_string(),
+                token: None
+            },
+            metrics: MetricsTable {
+                enabled: false,
+                address: "0.0.0.0:9027".to_string()
+            },
+            logging: LoggingTable {
+                filter: "info".to_string(),
+                json: false
+            },
+            capture: CaptureTable {
+                enabled: false,
+                file_name: "./capture.smocap".to_string()
             }
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum FlipOptions {
     BothOption,
     SelfOption,
     OthersOption
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct ServerTable {
+    // Read on startup
     pub address: String,
     pub port: u16,
+    // 0 uses `MAX_PLAYERS`, players over the limit are turned away until someone leaves
     pub max_players: u16,
 }
 
+impl ServerTable {
+    /// Fails rather than guessing when `address` doesn't parse, a typo mustn't listen on every interface
+    pub fn bind_address(&self) -> io::Result<SocketAddr> {
+        let ip = self.address.parse::<IpAddr>().map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("server.address {:?} isn't an IP address", self.address),
+        ))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    pub fn max_players(&self) -> u16 {
+        if self.max_players == 0 {
+            MAX_PLAYERS
+        } else {
+            self.max_players
+        }
+    }
+}
+
+/// Settings given as command line options or `SMO_*` environment variables, applied over the settings file
+#[derive(Clone, Default, Debug)]
+pub struct SettingsOverrides {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub max_players: Option<u16>,
+    // `logging.filter`
+    pub log: Option<String>,
+    // Also enables `persist_shines`
+    pub shines_file: Option<String>,
+    // Keeps the IP address from `admin.address`
+    pub admin_port: Option<u16>,
+}
+
+impl SettingsOverrides {
+    /// Reads `SMO_ADDRESS`, `SMO_PORT`, `SMO_MAX_PLAYERS`, `SMO_LOG`, `SMO_SHINES_FILE` and `SMO_ADMIN_PORT`, empty ones are ignored
+    pub fn from_env() -> Result<SettingsOverrides, String> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        let number = |name: &str| var(name).map(|value| parse_override(name, &value)).transpose();
+        Ok(SettingsOverrides {
+            address: var("SMO_ADDRESS"),
+            port: number("SMO_PORT")?,
+            max_players: number("SMO_MAX_PLAYERS")?,
+            log: var("SMO_LOG"),
+            shines_file: var("SMO_SHINES_FILE"),
+            admin_port: number("SMO_ADMIN_PORT")?,
+        })
+    }
+
+    /// Overrides set in `other` win
+    pub fn merge(self, other: SettingsOverrides) -> SettingsOverrides {
+        SettingsOverrides {
+            address: other.address.or(self.address),
+            port: other.port.or(self.port),
+            max_players: other.max_players.or(self.max_players),
+            log: other.log.or(self.log),
+            shines_file: other.shines_file.or(self.shines_file),
+            admin_port: other.admin_port.or(self.admin_port),
+        }
+    }
+
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(address) = &self.address {
+            settings.server.address = address.to_string();
+        }
+        if let Some(port) = self.port {
+            settings.server.port = port;
+        }
+        if let Some(max_players) = self.max_players {
+            settings.server.max_players = max_players;
+        }
+        if let Some(log) = &self.log {
+            settings.logging.filter = log.to_string();
+        }
+        if let Some(file_name) = &self.shines_file {
+            settings.persist_shines.enabled = true;
+            settings.persist_shines.file_name = file_name.to_string();
+        }
+        // An address that doesn't parse is left for `validate` to report
+        if let (Some(port), Ok(address)) = (self.admin_port, settings.admin.address.parse::<SocketAddr>()) {
+            settings.admin.address = SocketAddr::new(address.ip(), port).to_string();
+        }
+    }
+}
+
+/// Parses an option or environment variable, the error names it
+pub fn parse_override<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} has an invalid value {:?}", name, value))
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct ScenarioTable {
     pub merge_enabled: bool,
+    // Empty merges in every kingdom
+    pub merge_kingdoms: Vec<Kingdom>,
+    // Empty merges between every player, otherwise only between members of the same group
+    pub merge_groups: Vec<Vec<Uuid>>,
+    // Applies even when merging is disabled
+    pub fixed: Vec<FixedScenario>,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FixedScenario {
+    pub kingdom: Kingdom,
+    pub scenario: u8,
+}
+
+/// How a player's scenario is rewritten before it is relayed to others
+#[derive(Copy, Clone, PartialEq)]
+pub enum RelayedScenario {
+    Own(u8),
+    Merged,
+    Fixed(u8),
+}
+
+impl fmt::Display for RelayedScenario {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayedScenario::Own(scenario) => write!(f, "{}", scenario),
+            RelayedScenario::Merged => write!(f, "merged"),
+            RelayedScenario::Fixed(scenario) => write!(f, "fixed {}", scenario),
+        }
+    }
+}
+
+impl ScenarioTable {
+    pub fn fixed_scenario(&self, kingdom: Option<Kingdom>) -> Option<u8> {
+        let kingdom = kingdom?;
+        self.fixed.iter()
+            .find(|fixed| fixed.kingdom == kingdom)
+            .map(|fixed| fixed.scenario)
+    }
+
+    pub fn merges_kingdom(&self, kingdom: Option<Kingdom>) -> bool {
+        if !self.merge_enabled {
+            return false;
+        }
+        if self.merge_kingdoms.is_empty() {
+            return true;
+        }
+        match kingdom {
+            Some(kingdom) => self.merge_kingdoms.contains(&kingdom),
+            None => false,
+        }
+    }
+
+    pub fn merges_players(&self, from: &Uuid, to: &Uuid) -> bool {
+        self.merge_groups.is_empty() ||
+            self.merge_groups.iter().any(|group| group.contains(from) && group.contains(to))
+    }
+
+    pub fn relayed_scenario(&self, kingdom: Option<Kingdom>, scenario: u8) -> RelayedScenario {
+        if let Some(fixed) = self.fixed_scenario(kingdom) {
+            return RelayedScenario::Fixed(fixed);
+        }
+        if self.merges_kingdom(kingdom) {
+            return RelayedScenario::Merged;
+        }
+        RelayedScenario::Own(scenario)
+    }
+
+    /// Scenario the receiver should see the sender in, `None` leaves the packet untouched.
+    /// Receivers still on 200 haven't sent a GamePacket yet and are left alone
+    pub fn scenario_for(&self, kingdom: Option<Kingdom>, from: &Uuid, to: &Uuid, to_scenario: u8) -> Option<u8> {
+        if to_scenario == 200 {
+            return None;
+        }
+        match self.relayed_scenario(kingdom, to_scenario) {
+            RelayedScenario::Fixed(scenario) => Some(scenario),
+            RelayedScenario::Merged if self.merges_players(from, to) => Some(to_scenario),
+            _ => None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.merge_enabled || !self.fixed.is_empty()
+    }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BannedPlayers {
     pub enabled: bool,
     pub players: Vec<Uuid>,
     pub ip_addresses: Vec<String>,
 }
 
+impl BannedPlayers {
+    pub fn bans_player(&self, id: &Uuid) -> bool {
+        self.enabled && self.players.contains(id)
+    }
+
+    pub fn bans_address(&self, address: &IpAddr) -> bool {
+        self.enabled && self.ip_addresses.iter().any(|banned| banned.parse::<IpAddr>().is_ok_and(|banned| banned == *address))
+    }
+
+    /// The player's UUID or the address they connected from is banned
+    pub fn bans_client(&self, id: &Uuid, address: Option<SocketAddr>) -> bool {
+        self.bans_player(id) || address.is_some_and(|address| self.bans_address(&address.ip()))
+    }
+}
+
+/// While enabled, only members and players sending the password can join
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AllowedPlayers {
+    pub enabled: bool,
+    pub players: Vec<Uuid>,
+    // Matched ignoring case. Anyone can pick a name, so UUIDs are safer
+    pub names: Vec<String>,
+    // For mods set up to send one, lets in anyone who sends it
+    pub password: Option<String>,
+}
+
+impl AllowedPlayers {
+    /// Always true while disabled. `guests` are UUIDs or names let in from the console
+    pub fn allows(&self, id: &Uuid, name: &str, password: &str, guests: &[String]) -> bool {
+        !self.enabled
+            || self.players.contains(id)
+            || self.names.iter().chain(guests).any(|member| is_member(member, id, name))
+            || self.password.as_ref().is_some_and(|expected| !expected.is_empty() && expected == password)
+    }
+}
+
+/// A UUID matches the player's, anything else is a name
+fn is_member(member: &str, id: &Uuid, name: &str) -> bool {
+    match member.parse::<Uuid>() {
+        Ok(member) => member == *id,
+        Err(_) => member.eq_ignore_ascii_case(name),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FlipTable {
     pub enabled: bool,
     pub players: Vec<Uuid>,
     pub pov: FlipOptions,
 }
 
+impl FlipTable {
+    /// Listed players are flipped for everyone with Self or Both.
+    /// Others: listed players don't see each other flipped.
+    pub fn flips(&self, viewer: &Uuid, subject: &Uuid) -> bool {
+        self.enabled
+            && self.players.contains(subject)
+            && (self.pov != FlipOptions::OthersOption || !self.players.contains(viewer))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransformTable {
+    pub pairs: Vec<PairTransform>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PairTransform {
+    // None matches every player
+    pub viewer: Option<Uuid>,
+    pub subject: Option<Uuid>,
+    pub flip: bool,
+    pub offset_scale: f32,
+    pub mirror: bool,
+}
+
+impl PairTransform {
+    pub fn matches(&self, viewer: &Uuid, subject: &Uuid) -> bool {
+        self.viewer.is_none_or(|id| id == *viewer) && self.subject.is_none_or(|id| id == *subject)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct DiscordTable {
     pub token: Option<String>,
     pub prefix: String,
@@ -86,12 +472,153 @@ pub struct DiscordTable {
     pub log_channel: Option<String>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SmoothingTable {
+    pub enabled: bool,
+    // Smoothed PlayerPackets sent per second, read on startup
+    pub tick_rate: u16,
+    // How far behind real time players are rendered so there are samples to interpolate between
+    pub interpolation_delay_ms: u64,
+    // How far past the newest sample a player keeps moving before they freeze in place
+    pub max_extrapolation_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TickTable {
+    // Batch Player and Cap packets per tick instead of relaying each one as it arrives
+    pub enabled: bool,
+    // Batches sent per second, read on startup
+    pub tick_rate: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RateLimitTable {
+    pub enabled: bool,
+    // Per player, PlayerPackets and CapPackets over the limit are dropped until the next second
+    pub packets_per_second: u32,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct ShineTable {
     pub enabled: bool,
 }
 
-pub struct PersistShinesTable
-{
+// Written on shutdown and read back on startup, except stats which start from zero every run. See persist.rs
+#[derive(Serialize, Deserialize)]
+pub struct PersistTable {
+    pub enabled: bool,
+    pub file_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShutdownTable {
+    // How long players' queued packets get to be written before their sockets are dropped
+    pub drain_timeout_ms: u64,
+}
+
+// Needs the admin-api feature, the address and token are read on startup
+#[derive(Serialize, Deserialize)]
+pub struct AdminTable {
+    pub enabled: bool,
+    pub address: String,
+    // Sent as `Authorization: Bearer <token>`, the API won't start without one
+    pub token: Option<String>,
+}
+
+// Prometheus scrapes `GET /metrics` on this address, read on startup
+#[derive(Serialize, Deserialize)]
+pub struct MetricsTable {
+    pub enabled: bool,
+    pub address: String,
+}
+
+// Read on startup, `SMO_LOG` or `--log` override `filter`
+#[derive(Serialize, Deserialize)]
+pub struct LoggingTable {
+    // tracing-subscriber directives, e.g. "info,smo_rusty_online::server=debug"
+    pub filter: String,
+    // One JSON object per line for log shippers
+    pub json: bool,
+}
+
+// Records every frame for `--replay`, see capture.rs for the format
+#[derive(Serialize, Deserialize)]
+pub struct CaptureTable {
     pub enabled: bool,
     pub file_name: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenarios() -> ScenarioTable {
+        Settings::defaults().scenario
+    }
+
+    #[test]
+    fn inactive_table_leaves_scenarios_alone() {
+        let table = ScenarioTable { merge_enabled: false, ..scenarios() };
+        let (from, to) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(!table.is_active());
+        assert!(table.relayed_scenario(Some(Kingdom::Sand), 3) == RelayedScenario::Own(3));
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &from, &to, 5), None);
+    }
+
+    #[test]
+    fn merging_can_be_limited_to_kingdoms() {
+        let table = ScenarioTable { merge_enabled: true, merge_kingdoms: vec![Kingdom::Sand], ..scenarios() };
+        let (from, to) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &from, &to, 5), Some(5));
+        assert_eq!(table.scenario_for(Some(Kingdom::Lake), &from, &to, 5), None);
+        // Outside any kingdom, e.g. before the first home stage
+        assert_eq!(table.scenario_for(None, &from, &to, 5), None);
+
+        let every_kingdom = ScenarioTable { merge_enabled: true, ..scenarios() };
+        assert_eq!(every_kingdom.scenario_for(Some(Kingdom::Lake), &from, &to, 5), Some(5));
+    }
+
+    #[test]
+    fn groups_only_merge_their_members() {
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let table = ScenarioTable { merge_enabled: true, merge_groups: vec![vec![alice, bob]], ..scenarios() };
+        assert!(table.merges_players(&alice, &bob));
+        assert!(!table.merges_players(&alice, &carol));
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &alice, &bob, 5), Some(5));
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &alice, &carol, 5), None);
+    }
+
+    #[test]
+    fn fixed_scenarios_win_over_groups_and_kingdoms() {
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let table = ScenarioTable {
+            merge_enabled: true,
+            merge_kingdoms: vec![Kingdom::Sand],
+            merge_groups: vec![vec![alice, bob]],
+            fixed: vec![FixedScenario { kingdom: Kingdom::Sand, scenario: 2 }],
+        };
+        assert_eq!(table.fixed_scenario(Some(Kingdom::Sand)), Some(2));
+        assert_eq!(table.fixed_scenario(None), None);
+        assert!(table.relayed_scenario(Some(Kingdom::Sand), 5) == RelayedScenario::Fixed(2));
+        // Whoever is watching, group member or not
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &alice, &bob, 5), Some(2));
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &alice, &carol, 5), Some(2));
+
+        // Fixed scenarios apply even with merging off
+        let table = ScenarioTable { merge_enabled: false, ..table };
+        assert!(table.is_active());
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &alice, &carol, 5), Some(2));
+    }
+
+    #[test]
+    fn receivers_still_on_200_are_left_alone() {
+        let (from, to) = (Uuid::new_v4(), Uuid::new_v4());
+        let table = ScenarioTable {
+            merge_enabled: true,
+            fixed: vec![FixedScenario { kingdom: Kingdom::Sand, scenario: 2 }],
+            ..scenarios()
+        };
+        assert_eq!(table.scenario_for(Some(Kingdom::Lake), &from, &to, 200), None);
+        assert_eq!(table.scenario_for(Some(Kingdom::Sand), &from, &to, 200), None);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::Duration
+};
+use tokio::{
+    sync::{RwLock, broadcast},
+    time::sleep
+};
+use tracing::warn;
+use crate::{
+    client::Client,
+    events::ServerEvent,
+    registry::DisconnectReason,
+    packet::{
+        PacketHeader::PacketHeader,
+        PacketType::PacketType,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            ShinePacket::ShinePacket
+        }
+    },
+    server::{Server, ServerWrapper}
+};
+
+// Leaving Cascade after a speedrun start waits this long before syncing, like the reference server
+pub const SPEEDRUN_SYNC_DELAY: Duration = Duration::from_secs(15);
+
+/// Every shine collected by anyone since the server started
+pub struct ShineBag {
+    shines: Mutex<BTreeSet<u32>>,
+}
+
+impl Default for ShineBag {
+    fn default() -> Self {
+        ShineBag::new()
+    }
+}
+
+impl ShineBag {
+    pub fn new() -> Self {
+        ShineBag {
+            shines: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn insert(&self, shine_id: u32) -> bool {
+        self.shines.lock().unwrap().insert(shine_id)
+    }
+
+    pub fn all(&self) -> Vec<u32> {
+        self.shines.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Shares collected shines between players
+/// https://github.com/Sanae6/SmoOnlineServer/blob/e14616030cea51d1508665d8c1e4743e9c70c290/Server/Program.cs#L128-L178
+pub struct ShineSync;
+
+impl ShineSync {
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let mut events = server.read().await.events.subscribe();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Shine sync fell behind and skipped events");
+                    continue;
+                },
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            if !server.read().await.settings.shine.enabled {
+                continue;
+            }
+
+            match event {
+                ServerEvent::ShineCollected { id, shine_id } => {
+                    server.read().await.shines.insert(shine_id);
+                    let players = server.read().await.players.clone();
+                    if let Some(player) = players.get(&id) {
+                        player.client.write().await.metadata.shine_sync.push(shine_id as usize);
+                    }
+                    ShineSync::sync_all(server.clone()).await;
+                },
+                // The first CostumePacket means the save file has loaded
+                ServerEvent::CostumeChanged { id, .. } => {
+                    let players = server.read().await.players.clone();
+                    if let Some(player) = players.get(&id) {
+                        ShineSync::sync(server.clone(), player.client).await;
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    pub async fn sync_all(server: Arc<RwLock<Server>>) -> usize {
+        let players = server.read().await.players.clone();
+        let mut sent = 0;
+        for player in players.connected() {
+            sent += ShineSync::sync(server.clone(), player.client).await;
+        }
+        sent
+    }
+
+    /// Sends the client every shine it hasn't got yet, players on a speedrun are left alone
+    pub async fn sync(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>) -> usize {
+        let shines = server.read().await.shines.clone();
+        let missing: Vec<u32> = {
+            let c = client.read().await;
+            if c.metadata.speedrun || !c.metadata.loaded_save {
+                return 0;
+            }
+            shines.all()
+                .into_iter()
+                .filter(|shine_id| !c.metadata.shine_sync.contains(&(*shine_id as usize)))
+                .collect()
+        };
+
+        let mut sent = 0;
+        for shine_id in missing {
+            let mut shine_packet = IPacket::<ShinePacket>::new();
+            shine_packet.packet.shine_id = shine_id;
+
+            let mut packet_header = IPacket::<PacketHeader>::new();
+            packet_header.packet.id = client.read().await.id;
+            packet_header.packet.packet_type = PacketType::Shine;
+            packet_header.packet.packet_size = shine_packet.get_size().to_owned() as i16;
+
+            if !client.read().await.send(&packet_header, &shine_packet).await {
+                ServerWrapper::disconnect(server.clone(), client.clone(), DisconnectReason::SendFailed).await;
+                break;
+            }
+            client.write().await.metadata.shine_sync.push(shine_id as usize);
+            sent += 1;
+        }
+        sent
+    }
+
+    pub async fn sync_later(server: Arc<RwLock<Server>>, client: Arc<RwLock<Client>>, delay: Duration) {
+        sleep(delay).await;
+        ShineSync::sync(server, client).await;
+    }
+}
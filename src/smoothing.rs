@@ -0,0 +1,296 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant}
+};
+use nalgebra::{Vector3, Quaternion};
+use tokio::{
+    sync::RwLock,
+    time::{interval, MissedTickBehavior}
+};
+use crate::{
+    packet::{
+        PacketHeader::PacketHeader,
+        PacketType::PacketType,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            PlayerPacket::{
+                PlayerPacket,
+                ANIMATION_WEIGHT_SIZE
+            }
+        }
+    },
+    hooks::HookPacket,
+    server::{Server, ServerWrapper},
+    settings::SmoothingTable,
+    lib::rot::QuaternionRotation
+};
+
+pub const HISTORY_SIZE: usize = 16;
+
+#[derive(Copy, Clone)]
+pub struct MotionSample {
+    pub when: Instant,
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub animation_blend_weights: [f32; ANIMATION_WEIGHT_SIZE],
+    pub act: u16,
+    pub sub_act: u16,
+}
+
+/// Recent PlayerPackets of one client, oldest first
+pub struct MotionHistory {
+    pub samples: VecDeque<MotionSample>,
+    pub capacity: usize,
+}
+
+impl MotionHistory {
+    pub fn new(capacity: usize) -> Self {
+        MotionHistory {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(2),
+        }
+    }
+
+    /// Kept in time order even if `when` is older than the newest sample
+    pub fn push(&mut self, packet: &PlayerPacket, when: Instant) {
+        let index = self.samples.iter().rposition(|sample| sample.when <= when).map_or(0, |index| index + 1);
+        self.samples.insert(index, MotionSample {
+            when,
+            position: packet.position,
+            rotation: packet.rotation,
+            animation_blend_weights: packet.animation_blend_weights,
+            act: packet.act,
+            sub_act: packet.sub_act,
+        });
+        while self.samples.len() > self.capacity {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Interpolates between the samples around `at`, or extrapolates from the last two
+    /// for at most `max_extrapolation` past the newest one
+    pub fn sample_at(&self, at: Instant, max_extrapolation: Duration) -> Option<MotionSample> {
+        let newest = *self.samples.back()?;
+        let oldest = *self.samples.front()?;
+
+        if at <= oldest.when {
+            return Some(oldest);
+        }
+
+        if at >= newest.when {
+            let previous = match self.samples.len() {
+                0 | 1 => return Some(newest),
+                len => self.samples[len - 2],
+            };
+            let step = newest.when.duration_since(previous.when).as_secs_f32();
+            if step <= 0.0 {
+                return Some(newest);
+            }
+            let ahead = at.duration_since(newest.when).min(max_extrapolation).as_secs_f32();
+            let velocity = (newest.position - previous.position) / step;
+
+            let mut sample = newest;
+            sample.when = at;
+            sample.position = newest.position + velocity * ahead;
+            return Some(sample);
+        }
+
+        let index = self.samples.iter().position(|sample| sample.when > at)?;
+        let before = self.samples[index - 1];
+        let after = self.samples[index];
+        let span = after.when.duration_since(before.when).as_secs_f32();
+        let t = if span > 0.0 {
+            at.duration_since(before.when).as_secs_f32() / span
+        } else {
+            1.0
+        };
+
+        let mut animation_blend_weights = [0.0; ANIMATION_WEIGHT_SIZE];
+        for (i, weight) in animation_blend_weights.iter_mut().enumerate() {
+            *weight = before.animation_blend_weights[i] + (after.animation_blend_weights[i] - before.animation_blend_weights[i]) * t;
+        }
+        // Animations can't be blended, take whichever sample is closer
+        let nearest = if t < 0.5 { before } else { after };
+
+        Some(MotionSample {
+            when: at,
+            position: before.position + (after.position - before.position) * t,
+            rotation: before.rotation.slerp(&after.rotation, t),
+            animation_blend_weights,
+            act: nearest.act,
+            sub_act: nearest.sub_act,
+        })
+    }
+
+    pub fn newest(&self) -> Option<&MotionSample> {
+        self.samples.back()
+    }
+}
+
+pub struct Smoother;
+
+impl Smoother {
+    /// The PlayerPacket to relay for a client right now, `None` once it stopped sending
+    pub fn smoothed_packet(motion: &MotionHistory, now: Instant, settings: &SmoothingTable) -> Option<IPacket<PlayerPacket>> {
+        let delay = Duration::from_millis(settings.interpolation_delay_ms);
+        let max_extrapolation = Duration::from_millis(settings.max_extrapolation_ms);
+
+        // Stop relaying players that stopped sending instead of sliding them away
+        let newest = motion.newest()?;
+        if now.duration_since(newest.when) > delay + max_extrapolation {
+            return None;
+        }
+
+        let render_at = now.checked_sub(delay).unwrap_or(now);
+        let sample = motion.sample_at(render_at, max_extrapolation)?;
+
+        let mut player_packet = IPacket::<PlayerPacket>::new();
+        player_packet.packet.position = sample.position;
+        player_packet.packet.rotation = sample.rotation;
+        player_packet.packet.animation_blend_weights = sample.animation_blend_weights;
+        player_packet.packet.act = sample.act;
+        player_packet.packet.sub_act = sample.sub_act;
+        Some(player_packet)
+    }
+
+    /// Relays one smoothed PlayerPacket per client every tick while smoothing is enabled,
+    /// the batching tick loop takes over when that is enabled too
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let tick_rate = server.read().await.settings.smoothing.tick_rate.max(1);
+        let mut ticker = interval(Duration::from_secs_f64(1.0 / tick_rate as f64));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            {
+                let settings = &server.read().await.settings;
+                if !settings.smoothing.enabled || settings.tick.enabled {
+                    continue;
+                }
+            }
+
+            let now = Instant::now();
+            // Copied out so the server lock is never awaited while holding a client's
+            let (players, settings) = {
+                let s = server.read().await;
+                (s.players.clone(), s.settings.smoothing.clone())
+            };
+            for player in players.connected() {
+                let client = player.client;
+                let player_packet = Smoother::smoothed_packet(&client.read().await.metadata.motion, now, &settings);
+
+                if let Some(player_packet) = player_packet {
+                    let mut packet_header = IPacket::<PacketHeader>::new();
+                    packet_header.packet.id = player.id;
+                    packet_header.packet.packet_type = PacketType::Player;
+                    packet_header.packet.packet_size = player_packet.get_size().to_owned() as i16;
+
+                    ServerWrapper::relay(server.clone(), client.clone(), HookPacket::new(&packet_header, &player_packet)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+    const MAX_EXTRAPOLATION: Duration = Duration::from_millis(100);
+
+    fn ms(milliseconds: u64) -> Duration {
+        Duration::from_millis(milliseconds)
+    }
+
+    fn packet(x: f32, rotation: Quaternion<f32>, act: u16) -> PlayerPacket {
+        let mut packet = IPacket::<PlayerPacket>::new().packet;
+        packet.position = Vector3::new(x, 0.0, 0.0);
+        packet.rotation = rotation;
+        packet.animation_blend_weights[0] = x;
+        packet.act = act;
+        packet
+    }
+
+    fn history(start: Instant, samples: &[(u64, f32)]) -> MotionHistory {
+        let mut history = MotionHistory::new(HISTORY_SIZE);
+        for (at, x) in samples {
+            history.push(&packet(*x, Quaternion::identity(), 0), start + ms(*at));
+        }
+        history
+    }
+
+    #[test]
+    fn empty_history_has_no_sample() {
+        assert!(MotionHistory::new(HISTORY_SIZE).sample_at(Instant::now(), MAX_EXTRAPOLATION).is_none());
+    }
+
+    #[test]
+    fn single_sample_is_held() {
+        let start = Instant::now();
+        let history = history(start, &[(0, 5.0)]);
+        for at in [start, start + ms(50), start + ms(500)] {
+            assert_eq!(history.sample_at(at, MAX_EXTRAPOLATION).unwrap().position.x, 5.0);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let start = Instant::now();
+        let quarter_turn = Quaternion::<f32>::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), PI / 2.0);
+        let mut history = MotionHistory::new(HISTORY_SIZE);
+        history.push(&packet(0.0, Quaternion::identity(), 1), start);
+        history.push(&packet(10.0, quarter_turn, 2), start + ms(100));
+
+        let sample = history.sample_at(start + ms(25), MAX_EXTRAPOLATION).unwrap();
+        assert!((sample.position.x - 2.5).abs() < EPSILON);
+        assert!((sample.animation_blend_weights[0] - 2.5).abs() < EPSILON);
+        assert_eq!(sample.act, 1);
+
+        // Halfway round the quarter turn
+        let eighth_turn = Quaternion::<f32>::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), PI / 4.0);
+        let sample = history.sample_at(start + ms(50), MAX_EXTRAPOLATION).unwrap();
+        assert!((sample.rotation.dot(&eighth_turn).abs() - 1.0).abs() < EPSILON, "{:?}", sample.rotation);
+        assert_eq!(sample.act, 2);
+    }
+
+    #[test]
+    fn holds_the_oldest_sample_before_the_history() {
+        let start = Instant::now() + ms(1000);
+        let history = history(start, &[(0, 1.0), (100, 2.0)]);
+        assert_eq!(history.sample_at(start - ms(500), MAX_EXTRAPOLATION).unwrap().position.x, 1.0);
+    }
+
+    #[test]
+    fn extrapolation_stops_at_the_limit() {
+        let start = Instant::now();
+        // 1 unit per 10ms
+        let history = history(start, &[(0, 0.0), (10, 1.0)]);
+
+        let sample = history.sample_at(start + ms(60), MAX_EXTRAPOLATION).unwrap();
+        assert!((sample.position.x - 6.0).abs() < EPSILON);
+        let sample = history.sample_at(start + ms(1000), MAX_EXTRAPOLATION).unwrap();
+        assert!((sample.position.x - 11.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn late_samples_are_put_in_order() {
+        let start = Instant::now();
+        let history = history(start, &[(0, 0.0), (200, 20.0), (100, 10.0)]);
+        let times: Vec<Instant> = history.samples.iter().map(|sample| sample.when).collect();
+        assert_eq!(times, vec![start, start + ms(100), start + ms(200)]);
+
+        let sample = history.sample_at(start + ms(150), MAX_EXTRAPOLATION).unwrap();
+        assert!((sample.position.x - 15.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn samples_at_the_same_time_do_not_extrapolate() {
+        let start = Instant::now();
+        let history = history(start, &[(0, 0.0), (0, 4.0)]);
+        assert_eq!(history.sample_at(start + ms(50), MAX_EXTRAPOLATION).unwrap().position.x, 4.0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+// Scenario numbers the mod sends for kingdoms, -1 in a ChangeStagePacket keeps the current one
+pub const MIN_SCENARIO: i8 = 0;
+pub const MAX_SCENARIO: i8 = 15;
+pub const KEEP_SCENARIO: i8 = -1;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Kingdom {
+    Cap,
+    Cascade,
+    Sand,
+    Lake,
+    Wooded,
+    Cloud,
+    Lost,
+    Metro,
+    Snow,
+    Seaside,
+    Luncheon,
+    Ruined,
+    Bowsers,
+    Moon,
+    Mushroom,
+    DarkSide,
+    DarkerSide,
+    Odyssey,
+}
+
+pub struct KingdomInfo {
+    pub kingdom: Kingdom,
+    pub name: &'static str,
+    pub home_stage: &'static str,
+    pub aliases: &'static [&'static str],
+}
+
+pub const KINGDOMS: [KingdomInfo; 18] = [
+    KingdomInfo { kingdom: Kingdom::Cap, name: "Cap Kingdom", home_stage: "CapWorldHomeStage", aliases: &["cap"] },
+    KingdomInfo { kingdom: Kingdom::Cascade, name: "Cascade Kingdom", home_stage: "WaterfallWorldHomeStage", aliases: &["cascade", "waterfall"] },
+    KingdomInfo { kingdom: Kingdom::Sand, name: "Sand Kingdom", home_stage: "SandWorldHomeStage", aliases: &["sand"] },
+    KingdomInfo { kingdom: Kingdom::Lake, name: "Lake Kingdom", home_stage: "LakeWorldHomeStage", aliases: &["lake"] },
+    KingdomInfo { kingdom: Kingdom::Wooded, name: "Wooded Kingdom", home_stage: "ForestWorldHomeStage", aliases: &["wooded", "forest"] },
+    KingdomInfo { kingdom: Kingdom::Cloud, name: "Cloud Kingdom", home_stage: "CloudWorldHomeStage", aliases: &["cloud"] },
+    KingdomInfo { kingdom: Kingdom::Lost, name: "Lost Kingdom", home_stage: "ClashWorldHomeStage", aliases: &["lost", "clash"] },
+    KingdomInfo { kingdom: Kingdom::Metro, name: "Metro Kingdom", home_stage: "CityWorldHomeStage", aliases: &["metro", "city"] },
+    KingdomInfo { kingdom: Kingdom::Snow, name: "Snow Kingdom", home_stage: "SnowWorldHomeStage", aliases: &["snow"] },
+    KingdomInfo { kingdom: Kingdom::Seaside, name: "Seaside Kingdom", home_stage: "SeaWorldHomeStage", aliases: &["seaside", "sea"] },
+    KingdomInfo { kingdom: Kingdom::Luncheon, name: "Luncheon Kingdom", home_stage: "LavaWorldHomeStage", aliases: &["luncheon", "lunch", "lava"] },
+    KingdomInfo { kingdom: Kingdom::Ruined, name: "Ruined Kingdom", home_stage: "BossRaidWorldHomeStage", aliases: &["ruined"] },
+    KingdomInfo { kingdom: Kingdom::Bowsers, name: "Bowser's Kingdom", home_stage: "SkyWorldHomeStage", aliases: &["bowser", "bowsers", "sky"] },
+    KingdomInfo { kingdom: Kingdom::Moon, name: "Moon Kingdom", home_stage: "MoonWorldHomeStage", aliases: &["moon"] },
+    KingdomInfo { kingdom: Kingdom::Mushroom, name: "Mushroom Kingdom", home_stage: "PeachWorldHomeStage", aliases: &["mushroom", "mush", "peach"] },
+    KingdomInfo { kingdom: Kingdom::DarkSide, name: "Dark Side", home_stage: "Special1WorldHomeStage", aliases: &["dark"] },
+    KingdomInfo { kingdom: Kingdom::DarkerSide, name: "Darker Side", home_stage: "Special2WorldHomeStage", aliases: &["darker"] },
+    KingdomInfo { kingdom: Kingdom::Odyssey, name: "Odyssey", home_stage: "HomeShipInsideStage", aliases: &["odyssey", "ship"] },
+];
+
+impl Kingdom {
+    pub fn info(&self) -> &'static KingdomInfo {
+        KINGDOMS.iter().find(|k| k.kingdom == *self).unwrap()
+    }
+}
+
+impl fmt::Display for Kingdom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.info().name)
+    }
+}
+
+/// Finds a kingdom by alias, friendly name or home stage name, ignoring case
+pub fn resolve(name: &str) -> Option<&'static KingdomInfo> {
+    let name = name.trim();
+    KINGDOMS.iter().find(|k| {
+        k.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name)) ||
+            k.name.eq_ignore_ascii_case(name) ||
+            k.home_stage.eq_ignore_ascii_case(name)
+    })
+}
+
+/// Only home stages are catalogued, sub areas (e.g. `*ExStage`) return `None`
+pub fn kingdom_for_stage(stage: &str) -> Option<Kingdom> {
+    KINGDOMS.iter()
+        .find(|k| k.home_stage == stage)
+        .map(|k| k.kingdom)
+}
+
+/// Stage names are ASCII identifiers ending in `Stage`, anything else is garbage from the client
+pub fn is_plausible_stage(stage: &str) -> bool {
+    stage.len() > "Stage".len() &&
+        stage.ends_with("Stage") &&
+        stage.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn is_valid_scenario(scenario: i8) -> bool {
+    scenario == KEEP_SCENARIO || (MIN_SCENARIO..=MAX_SCENARIO).contains(&scenario)
+}
+
+/// Human readable name for logs, e.g. "Cascade Kingdom (WaterfallWorldHomeStage)"
+pub fn friendly_name(stage: &str) -> String {
+    match kingdom_for_stage(stage) {
+        Some(kingdom) => format!("{} ({})", kingdom, stage),
+        None => stage.to_string(),
+    }
+}
+
+/// Resolves the target of a warp command to a stage name.
+/// Accepts aliases and kingdom names, or any plausible raw stage name for sub areas.
+pub fn resolve_warp_target(target: &str, scenario: i8) -> Result<(String, i8), String> {
+    if !is_valid_scenario(scenario) {
+        return Err(format!("Invalid scenario {}, expected {} or {}..={}", scenario, KEEP_SCENARIO, MIN_SCENARIO, MAX_SCENARIO));
+    }
+
+    if let Some(kingdom) = resolve(target) {
+        return Ok((kingdom.home_stage.to_string(), scenario));
+    }
+
+    if is_plausible_stage(target) {
+        return Ok((target.to_string(), scenario));
+    }
+
+    Err(format!("Unknown stage {:?}, use `stages` to list kingdoms and aliases", target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_aliases_names_and_home_stages() {
+        for name in ["waterfall", "Cascade", " CASCADE KINGDOM ", "waterfallworldhomestage"] {
+            assert_eq!(resolve(name).map(|k| k.kingdom), Some(Kingdom::Cascade), "{:?}", name);
+        }
+        assert_eq!(resolve("bowser").map(|k| k.kingdom), Some(Kingdom::Bowsers));
+        assert!(resolve("waterfal").is_none());
+        assert!(resolve("").is_none());
+    }
+
+    #[test]
+    fn every_kingdom_resolves_to_itself() {
+        for info in KINGDOMS.iter() {
+            assert_eq!(resolve(info.name).map(|k| k.kingdom), Some(info.kingdom));
+            assert_eq!(kingdom_for_stage(info.home_stage), Some(info.kingdom));
+            assert!(is_plausible_stage(info.home_stage));
+        }
+    }
+
+    #[test]
+    fn plausible_stages() {
+        assert!(is_plausible_stage("SandWorldPyramid000Stage"));
+        assert!(!is_plausible_stage("Stage"));
+        assert!(!is_plausible_stage("SandWorld"));
+        assert!(!is_plausible_stage("Sand World Stage"));
+        assert!(!is_plausible_stage("../SandStage"));
+        assert!(!is_plausible_stage("SandWorldHomeStagé"));
+    }
+
+    #[test]
+    fn scenario_range() {
+        assert!(is_valid_scenario(KEEP_SCENARIO));
+        assert!(is_valid_scenario(MIN_SCENARIO));
+        assert!(is_valid_scenario(MAX_SCENARIO));
+        assert!(!is_valid_scenario(-2));
+        assert!(!is_valid_scenario(MAX_SCENARIO + 1));
+    }
+
+    #[test]
+    fn warp_targets() {
+        assert_eq!(resolve_warp_target("SAND", 2), Ok(("SandWorldHomeStage".to_string(), 2)));
+        assert_eq!(resolve_warp_target("SandWorldPyramid000Stage", -1), Ok(("SandWorldPyramid000Stage".to_string(), -1)));
+        assert!(resolve_warp_target("sand", 16).is_err());
+        assert!(resolve_warp_target("sand", -2).is_err());
+        assert!(resolve_warp_target("narnia", 0).is_err());
+    }
+}
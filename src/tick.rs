@@ -0,0 +1,126 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant}
+};
+use tokio::{
+    sync::RwLock,
+    time::{interval, MissedTickBehavior}
+};
+use tracing::info;
+use uuid::Uuid;
+use crate::{
+    constants::packet_to_type_map,
+    packet::{
+        PacketHeader::PacketHeader,
+        packets::{
+            IPacket::{IPacket, IPacketTrait}
+        }
+    },
+    registry::{PlayerEntry, DisconnectReason},
+    server::Server,
+    smoothing::Smoother,
+    hooks::{HookPacket, PlayerView}
+};
+
+struct Update {
+    sender: PlayerView,
+    packets: Vec<HookPacket>,
+}
+
+fn packet_header<T: IPacketTrait>(id: Uuid, packet: &T) -> IPacket<PacketHeader> {
+    let mut packet_header = IPacket::<PacketHeader>::new();
+    packet_header.packet.id = id;
+    packet_header.packet.packet_type = packet_to_type_map(packet.get_name());
+    packet_header.packet.packet_size = packet.get_size().to_owned() as i16;
+    packet_header
+}
+
+pub struct TickLoop;
+
+impl TickLoop {
+    /// Sends every peer one batch of the newest Player and Cap packets each tick while batching is enabled,
+    /// each packet still goes through the packet hooks
+    pub async fn start(server: Arc<RwLock<Server>>) {
+        let tick_rate = server.read().await.settings.tick.tick_rate.max(1);
+        let mut ticker = interval(Duration::from_secs_f64(1.0 / tick_rate as f64));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            if server.read().await.settings.tick.enabled {
+                TickLoop::flush(server.clone()).await;
+            }
+        }
+    }
+
+    pub async fn flush(server: Arc<RwLock<Server>>) {
+        let now = Instant::now();
+        // Copied out so the server lock is never awaited while holding a client's
+        let (players, smoothing) = {
+            let s = server.read().await;
+            (s.players.clone(), s.settings.smoothing.clone())
+        };
+        let connected = players.connected();
+
+        let mut peers: Vec<(PlayerEntry, PlayerView)> = Vec::with_capacity(connected.len());
+        let mut updates: Vec<Update> = Vec::new();
+        for player_entry in connected {
+            let mut c = player_entry.client.write().await;
+
+            let player = if smoothing.enabled {
+                Smoother::smoothed_packet(&c.metadata.motion, now, &smoothing)
+            } else {
+                c.metadata.pending_player.take()
+            };
+            let cap = c.metadata.pending_cap.take();
+
+            let mut packets: Vec<HookPacket> = Vec::new();
+            if let Some(player) = player {
+                packets.push(HookPacket::new(&packet_header(c.id, &player), &player));
+            }
+            if let Some(cap) = cap {
+                packets.push(HookPacket::new(&packet_header(c.id, &cap), &cap));
+            }
+
+            let view = PlayerView::of(&c);
+            if !packets.is_empty() {
+                updates.push(Update {
+                    sender: view.clone(),
+                    packets,
+                });
+            }
+            peers.push((player_entry.clone(), view));
+        }
+
+        if updates.is_empty() {
+            return;
+        }
+
+        let mut batches: Vec<(PlayerEntry, Vec<u8>)> = Vec::with_capacity(peers.len());
+        {
+            let s = server.read().await;
+            for (peer, receiver) in peers {
+                let mut batch: Vec<u8> = Vec::new();
+                for update in updates.iter().filter(|update| update.sender.id != peer.id) {
+                    for packet in update.packets.iter() {
+                        for relayed in s.hooks.relay(&s.settings, &update.sender, &receiver, packet.copy()) {
+                            batch.extend(relayed.frame());
+                        }
+                    }
+                }
+
+                if !batch.is_empty() {
+                    batches.push((peer, batch));
+                }
+            }
+        }
+
+        for (peer, batch) in batches {
+            if !peer.send_frame(&batch) {
+                info!(player = %peer.name, "Disconnected, the send queue is full or closed");
+                players.disconnect(&peer.id, &peer.connected, DisconnectReason::SendFailed);
+            }
+        }
+    }
+}
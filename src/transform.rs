@@ -0,0 +1,234 @@
+use nalgebra::{Vector3, Quaternion};
+use uuid::Uuid;
+use crate::{
+    packet::packets::PlayerPacket::PlayerPacket,
+    settings::Settings
+};
+
+// Rx(PI) * Ry(PI), a half turn around Z that turns Mario upside down while he keeps facing the same way
+pub const FLIP_ROTATION: Quaternion<f32> = Quaternion { w: 0.0, i: 0.0, j: 0.0, k: 1.0 };
+
+pub fn mario_size(is_2d: bool) -> f32 {
+    if is_2d { 180.0 } else { 160.0 }
+}
+
+/// Adjustments to a subject's PlayerPacket before it is relayed to a viewer
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ViewTransform {
+    pub flip: bool,
+    // Vertical offset in multiples of Mario's height
+    pub offset_scale: f32,
+    // Reflect across the YZ plane
+    pub mirror: bool,
+}
+
+impl ViewTransform {
+    pub const IDENTITY: ViewTransform = ViewTransform {
+        flip: false,
+        offset_scale: 0.0,
+        mirror: false,
+    };
+
+    /// Combines the flip table with every matching entry of the transform table
+    pub fn for_pair(settings: &Settings, viewer: &Uuid, subject: &Uuid) -> ViewTransform {
+        let mut transform = ViewTransform::IDENTITY;
+        transform.flip = settings.flip.flips(viewer, subject);
+
+        for pair in settings.transforms.pairs.iter() {
+            if pair.matches(viewer, subject) {
+                transform.flip |= pair.flip;
+                transform.mirror |= pair.mirror;
+                transform.offset_scale += pair.offset_scale;
+            }
+        }
+
+        transform
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == ViewTransform::IDENTITY
+    }
+
+    /// Mirrors first, then flips in Mario's local frame, then offsets
+    pub fn apply(&self, packet: &mut PlayerPacket, is_2d: bool) {
+        if self.mirror {
+            packet.position.x = -packet.position.x;
+            packet.rotation = Quaternion::new(packet.rotation.w, packet.rotation.i, -packet.rotation.j, -packet.rotation.k);
+        }
+
+        let mut offset_scale = self.offset_scale;
+        if self.flip {
+            packet.rotation *= FLIP_ROTATION;
+            // Flipped around his feet, so lift him by his own height to keep him level with the original
+            offset_scale += 1.0;
+        }
+
+        packet.position += Vector3::new(0.0, 1.0, 0.0) * (offset_scale * mario_size(is_2d));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector3, Quaternion, UnitQuaternion};
+    use uuid::Uuid;
+    use crate::{
+        packet::packets::{
+            IPacket::{IPacket, IPacketTrait},
+            PlayerPacket::PlayerPacket
+        },
+        settings::{Settings, FlipOptions, PairTransform}
+    };
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn assert_vec_close(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!(
+            (actual.x - expected.x).abs() < EPSILON &&
+                (actual.y - expected.y).abs() < EPSILON &&
+                (actual.z - expected.z).abs() < EPSILON,
+            "{:?} != {:?}", actual, expected
+        );
+    }
+
+    // q and -q are the same rotation
+    fn assert_rotation_close(actual: Quaternion<f32>, expected: Quaternion<f32>) {
+        let dot = actual.w * expected.w + actual.i * expected.i + actual.j * expected.j + actual.k * expected.k;
+        assert!((dot.abs() - 1.0).abs() < EPSILON, "{:?} != {:?}", actual, expected);
+    }
+
+    fn rotate(q: Quaternion<f32>, v: Vector3<f32>) -> Vector3<f32> {
+        UnitQuaternion::new_with_quaternion(q) * v
+    }
+
+    fn yaw(radians: f32) -> Quaternion<f32> {
+        *UnitQuaternion::new(Vector3::new(0.0, radians, 0.0)).quaternion()
+    }
+
+    fn player(position: Vector3<f32>, rotation: Quaternion<f32>) -> IPacket<PlayerPacket> {
+        let mut packet = IPacket::<PlayerPacket>::new();
+        packet.packet.position = position;
+        packet.packet.rotation = rotation;
+        packet
+    }
+
+    fn flip_settings(pov: FlipOptions, players: Vec<Uuid>) -> Settings {
+        let mut settings = Settings::defaults();
+        settings.flip.enabled = true;
+        settings.flip.pov = pov;
+        settings.flip.players = players;
+        settings
+    }
+
+    #[test]
+    fn flip_rotation_matches_half_turns_around_x_then_y() {
+        let x = Quaternion::new(0.0, 1.0, 0.0, 0.0);
+        let y = Quaternion::new(0.0, 0.0, 1.0, 0.0);
+        assert_rotation_close(x * y, FLIP_ROTATION);
+    }
+
+    #[test]
+    fn flip_offsets_position_instead_of_replacing_it() {
+        let mut packet = player(Vector3::new(10.0, 20.0, 30.0), Quaternion::new(1.0, 0.0, 0.0, 0.0));
+        ViewTransform { flip: true, ..ViewTransform::IDENTITY }.apply(&mut packet.packet, false);
+        assert_vec_close(packet.packet.position, Vector3::new(10.0, 180.0, 30.0));
+
+        let mut packet = player(Vector3::new(10.0, 20.0, 30.0), Quaternion::new(1.0, 0.0, 0.0, 0.0));
+        ViewTransform { flip: true, ..ViewTransform::IDENTITY }.apply(&mut packet.packet, true);
+        assert_vec_close(packet.packet.position, Vector3::new(10.0, 200.0, 30.0));
+    }
+
+    #[test]
+    fn flip_turns_mario_upside_down_and_keeps_heading() {
+        let facing = yaw(0.7);
+        let mut packet = player(Vector3::new(0.0, 0.0, 0.0), facing);
+        ViewTransform { flip: true, ..ViewTransform::IDENTITY }.apply(&mut packet.packet, false);
+
+        assert_vec_close(rotate(packet.packet.rotation, Vector3::new(0.0, 1.0, 0.0)), Vector3::new(0.0, -1.0, 0.0));
+        assert_vec_close(
+            rotate(packet.packet.rotation, Vector3::new(0.0, 0.0, 1.0)),
+            rotate(facing, Vector3::new(0.0, 0.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn flipping_twice_restores_rotation() {
+        let facing = yaw(-1.3);
+        let mut packet = player(Vector3::new(0.0, 0.0, 0.0), facing);
+        let flip = ViewTransform { flip: true, ..ViewTransform::IDENTITY };
+        flip.apply(&mut packet.packet, false);
+        flip.apply(&mut packet.packet, false);
+        assert_rotation_close(packet.packet.rotation, facing);
+    }
+
+    #[test]
+    fn mirror_reflects_position_and_heading() {
+        let mut packet = player(Vector3::new(5.0, 6.0, 7.0), yaw(0.5));
+        ViewTransform { mirror: true, ..ViewTransform::IDENTITY }.apply(&mut packet.packet, false);
+
+        assert_vec_close(packet.packet.position, Vector3::new(-5.0, 6.0, 7.0));
+        assert_rotation_close(packet.packet.rotation, yaw(-0.5));
+    }
+
+    #[test]
+    fn offset_scale_uses_mario_size() {
+        let mut packet = player(Vector3::new(0.0, 0.0, 0.0), Quaternion::new(1.0, 0.0, 0.0, 0.0));
+        ViewTransform { offset_scale: 0.5, ..ViewTransform::IDENTITY }.apply(&mut packet.packet, false);
+        assert_vec_close(packet.packet.position, Vector3::new(0.0, 80.0, 0.0));
+    }
+
+    #[test]
+    fn flip_options_pick_the_same_pairs_as_before() {
+        let flipped = Uuid::new_v4();
+        let also_flipped = Uuid::new_v4();
+        let normal = Uuid::new_v4();
+
+        // Others: listed players appear flipped to everyone who isn't listed
+        let settings = flip_settings(FlipOptions::OthersOption, vec![flipped, also_flipped]);
+        assert!(ViewTransform::for_pair(&settings, &normal, &flipped).flip);
+        assert!(!ViewTransform::for_pair(&settings, &also_flipped, &flipped).flip);
+        assert!(!ViewTransform::for_pair(&settings, &flipped, &normal).flip);
+
+        // Self and Both: listed players appear flipped to everyone
+        for pov in [FlipOptions::SelfOption, FlipOptions::BothOption] {
+            let settings = flip_settings(pov, vec![flipped, also_flipped]);
+            assert!(ViewTransform::for_pair(&settings, &normal, &flipped).flip);
+            assert!(ViewTransform::for_pair(&settings, &also_flipped, &flipped).flip);
+            assert!(!ViewTransform::for_pair(&settings, &flipped, &normal).flip);
+        }
+
+        let mut settings = flip_settings(FlipOptions::BothOption, vec![flipped]);
+        settings.flip.enabled = false;
+        assert!(ViewTransform::for_pair(&settings, &normal, &flipped).is_identity());
+    }
+
+    #[test]
+    fn pair_transforms_only_match_their_pair() {
+        let viewer = Uuid::new_v4();
+        let subject = Uuid::new_v4();
+        let mut settings = Settings::defaults();
+        settings.transforms.pairs.push(PairTransform {
+            viewer: Some(viewer),
+            subject: None,
+            flip: false,
+            offset_scale: 2.0,
+            mirror: true,
+        });
+
+        let transform = ViewTransform::for_pair(&settings, &viewer, &subject);
+        assert!(transform.mirror);
+        assert_eq!(transform.offset_scale, 2.0);
+        assert!(ViewTransform::for_pair(&settings, &subject, &viewer).is_identity());
+    }
+
+    #[test]
+    fn rotation_is_relayed_in_wire_order() {
+        let mut packet = player(Vector3::new(0.0, 0.0, 0.0), Quaternion::new(4.0, 1.0, 2.0, 3.0));
+        let data = packet.serialize();
+        assert_eq!(data[12..16], 1.0f32.to_le_bytes());
+        assert_eq!(data[24..28], 4.0f32.to_le_bytes());
+
+        packet.deserialize(&data);
+        assert_eq!(packet.packet.rotation, Quaternion::new(4.0, 1.0, 2.0, 3.0));
+    }
+}
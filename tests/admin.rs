@@ -0,0 +1,97 @@
+#![cfg(feature = "admin-api")]
+use std::{
+    net::{SocketAddr, TcpListener as StdListener},
+    time::Duration
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time
+};
+use smo_rusty_online::{
+    bot::Bot,
+    builder::ServerBuilder,
+    events::ServerEvent,
+    settings::Settings
+};
+
+const TOKEN: &str = "admin secret";
+
+// One request per connection, the admin API may take a moment to start listening
+async fn request(address: SocketAddr, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, TOKEN, body.len(), body
+    );
+
+    let mut socket = time::timeout(Duration::from_secs(2), async {
+        loop {
+            match TcpStream::connect(address).await {
+                Ok(socket) => return socket,
+                Err(_) => time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }).await.expect("the admin API didn't start");
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn settings_hide_secrets() {
+    let address = StdListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut settings = Settings::defaults();
+    settings.admin.enabled = true;
+    settings.admin.address = address.to_string();
+    settings.admin.token = Some(TOKEN.to_string());
+    settings.discord.token = Some("discord secret".to_string());
+    settings.allowed_players.password = Some("hunter2".to_string());
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+
+    let (status, settings) = request(address, "GET", "/settings", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(settings["admin"]["token"], "[redacted]");
+    assert_eq!(settings["discord"]["token"], "[redacted]");
+    assert_eq!(settings["allowed_players"]["password"], "[redacted]");
+    assert!(!settings.to_string().contains("secret") && !settings.to_string().contains("hunter2"));
+
+    // Sending the redacted settings back keeps the secrets
+    let (status, _) = request(address, "PATCH", "/settings", Some(json!({
+        "discord": settings["discord"],
+        "allowed_players": { "password": "[redacted]", "enabled": true },
+    }))).await;
+    assert_eq!(status, 200);
+    {
+        let server = server.server();
+        let s = server.read().await;
+        assert_eq!(s.settings.discord.token.as_deref(), Some("discord secret"));
+        assert_eq!(s.settings.allowed_players.password.as_deref(), Some("hunter2"));
+        assert!(s.settings.allowed_players.enabled);
+    }
+
+    // Players the patched settings shut out are kicked before it returns
+    let mut events = server.server().read().await.events.subscribe();
+    let alice = Bot::connect_with_password(server.local_addr(), "alice", "hunter2").await.unwrap();
+    time::timeout(Duration::from_secs(2), async {
+        while !matches!(events.recv().await.unwrap(), ServerEvent::PlayerJoined { .. }) {}
+    }).await.expect("alice didn't join");
+    let (status, _) = request(address, "PATCH", "/settings", Some(json!({
+        "banned_players": { "enabled": true, "players": [alice.id] },
+    }))).await;
+    assert_eq!(status, 200);
+    assert!(!server.server().read().await.players.get(&alice.id).unwrap().is_connected());
+
+    // The token is only read on startup
+    let (status, error) = request(address, "PATCH", "/settings", Some(json!({ "admin": { "token": "new" } }))).await;
+    assert_eq!(status, 400);
+    assert!(error["error"].as_str().unwrap().contains("admin.token"), "{}", error);
+    assert_eq!(request(address, "GET", "/shines", None).await.0, 200);
+
+    server.shutdown().await.unwrap();
+}
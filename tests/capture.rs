@@ -0,0 +1,45 @@
+use std::io::ErrorKind;
+use uuid::Uuid;
+use smo_rusty_online::capture::{self, Direction, Record, MAGIC, MAX_RECORD_SIZE, VERSION};
+
+fn capture_file(records: &[Record]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    for record in records {
+        record.write_to(&mut bytes).unwrap();
+    }
+    bytes
+}
+
+#[test]
+fn records_are_read_back() {
+    let id = Uuid::new_v4();
+    let bytes = capture_file(&[
+        Record { micros: 5, direction: Direction::Inbound, connection: 1, id, frame: vec![1, 2, 3] },
+        Record { micros: 9, direction: Direction::Closed, connection: 1, id, frame: Vec::new() },
+    ]);
+
+    let records = capture::read_records(&mut &bytes[..]).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].frame, [1, 2, 3]);
+    assert_eq!(records[0].id, id);
+    assert_eq!(records[1].direction, Direction::Closed);
+}
+
+#[test]
+fn oversized_records_are_refused() {
+    let mut bytes = capture_file(&[]);
+    bytes.extend(0u64.to_le_bytes());
+    bytes.push(Direction::Inbound as u8);
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend(Uuid::nil().as_bytes());
+    // Claims 4GB and has none of it
+    bytes.extend(u32::MAX.to_le_bytes());
+    let error = capture::read_records(&mut &bytes[..]).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+    // The limit itself is fine
+    let frame = vec![0; MAX_RECORD_SIZE];
+    let bytes = capture_file(&[Record { micros: 0, direction: Direction::Outbound, connection: 0, id: Uuid::nil(), frame }]);
+    assert_eq!(capture::read_records(&mut &bytes[..]).unwrap()[0].frame.len(), MAX_RECORD_SIZE);
+}
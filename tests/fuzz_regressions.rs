@@ -0,0 +1,33 @@
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path
+};
+use smo_rusty_online::fuzz;
+
+// Inputs that crashed a fuzz target, in a directory named after the target
+const REGRESSIONS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions");
+
+#[test]
+fn fuzz_regressions_no_longer_crash() {
+    let mut failures = Vec::new();
+    let mut inputs = 0;
+
+    for target in fs::read_dir(REGRESSIONS).unwrap() {
+        let target = target.unwrap().path();
+        let name = target.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(fuzz::TARGETS.contains(&name.as_str()), "{} isn't a fuzz target", name);
+
+        for input in fs::read_dir(&target).unwrap() {
+            let input = input.unwrap().path();
+            let data = fs::read(&input).unwrap();
+            inputs += 1;
+            if panic::catch_unwind(AssertUnwindSafe(|| fuzz::run(&name, &data))).is_err() {
+                failures.push(input.strip_prefix(Path::new(REGRESSIONS)).unwrap().display().to_string());
+            }
+        }
+    }
+
+    assert!(inputs > 0, "no regression inputs in {}", REGRESSIONS);
+    assert!(failures.is_empty(), "crashed on {}", failures.join(", "));
+}
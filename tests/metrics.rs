@@ -0,0 +1,48 @@
+use std::{
+    net::{SocketAddr, TcpListener as StdListener},
+    time::Duration
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time
+};
+use smo_rusty_online::{
+    builder::ServerBuilder,
+    settings::Settings
+};
+
+// The endpoint may take a moment to start listening
+async fn connect(address: SocketAddr) -> TcpStream {
+    time::timeout(Duration::from_secs(2), async {
+        loop {
+            match TcpStream::connect(address).await {
+                Ok(socket) => return socket,
+                Err(_) => time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }).await.expect("the metrics endpoint didn't start")
+}
+
+#[tokio::test]
+async fn requests_split_over_several_writes_are_served() {
+    let address = StdListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut settings = Settings::defaults();
+    settings.metrics.enabled = true;
+    settings.metrics.address = address.to_string();
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+
+    // Sends nothing, and mustn't hold up anyone else
+    let _idle = connect(address).await;
+
+    let mut socket = connect(address).await;
+    socket.write_all(b"GET /metr").await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    socket.write_all(b"ics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    time::timeout(Duration::from_secs(2), socket.read_to_string(&mut response)).await.unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("smo_players_connected 0"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
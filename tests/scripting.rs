@@ -0,0 +1,34 @@
+#![cfg(feature = "scripting")]
+use std::{
+    env,
+    fs,
+    sync::mpsc,
+    thread,
+    time::Duration
+};
+use uuid::Uuid;
+use smo_rusty_online::scripting::ScriptHost;
+
+#[test]
+fn runaway_scripts_are_stopped() {
+    let dir = env::temp_dir().join(format!("smo-scripts-{}", Uuid::new_v4()));
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("fine.rhai"), "fn on_join(id, name) {}").unwrap();
+    fs::write(dir.join("loop.rhai"), "loop {}").unwrap();
+    fs::write(dir.join("recursion.rhai"), "fn deeper(n) { deeper(n + 1) } deeper(0);").unwrap();
+    fs::write(dir.join("string.rhai"), "let s = \"x\"; loop { s += s; }").unwrap();
+
+    // Reloading on another thread so a hang fails the test instead of stalling it
+    let (sender, reloaded) = mpsc::channel();
+    let host_dir = dir.clone();
+    thread::spawn(move || {
+        let _ = sender.send(ScriptHost::new(&host_dir).reload());
+    });
+    let message = reloaded.recv_timeout(Duration::from_secs(30)).expect("a runaway script hung the reload");
+
+    assert!(message.starts_with("Loaded 1 script(s)"), "{}", message);
+    for name in ["loop.rhai", "recursion.rhai", "string.rhai"] {
+        assert!(message.contains(&format!("Failed to load {}", name)), "{}", message);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
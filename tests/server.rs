@@ -0,0 +1,640 @@
+use std::{
+    env,
+    fs,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{RwLock, broadcast, mpsc},
+    time
+};
+use uuid::Uuid;
+use smo_rusty_online::{
+    bot::{self, Bot},
+    builder::ServerBuilder,
+    client::encode_frame,
+    events::ServerEvent,
+    hooks::{HookAction, HookPacket, PacketHook, PlayerView},
+    packet::{
+        PacketHeader::PacketHeader,
+        PacketType::PacketType,
+        packets::{
+            IPacket::{IPacket, IPacketTrait},
+            ConnectPacket::ConnectPacket,
+            CostumePacket::CostumePacket,
+            PlayerPacket::PlayerPacket,
+            ShinePacket::ShinePacket
+        }
+    },
+    registry::DisconnectReason,
+    server::{Server, ServerWrapper},
+    settings::{Settings, SettingsOverrides}
+};
+
+// Long enough to be sure a packet isn't coming
+const QUIET: Duration = Duration::from_millis(200);
+
+async fn start_server() -> (Arc<RwLock<Server>>, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = Arc::new(RwLock::new(Server::new(Settings::defaults())));
+    tokio::spawn(ServerWrapper::start(server.clone(), listener));
+    (server, address)
+}
+
+async fn next_event(events: &mut broadcast::Receiver<ServerEvent>, matches: impl Fn(&ServerEvent) -> bool) -> ServerEvent {
+    time::timeout(Duration::from_secs(2), async {
+        loop {
+            let event = events.recv().await.unwrap();
+            if matches(&event) {
+                return event;
+            }
+        }
+    }).await.expect("the event wasn't published")
+}
+
+fn frame<T: IPacketTrait>(id: Uuid, packet_type: PacketType, packet: &T) -> Vec<u8> {
+    let mut header = IPacket::<PacketHeader>::new();
+    header.packet.id = id;
+    header.packet.packet_type = packet_type;
+    header.packet.packet_size = *packet.get_size() as i16;
+    encode_frame(&header, packet)
+}
+
+// Reads whatever the server sends until it closes the socket
+async fn wait_for_close(mut socket: TcpStream) {
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(2), socket.read_to_end(&mut received)).await
+        .expect("the server didn't close the socket")
+        .ok();
+}
+
+#[tokio::test]
+async fn players_are_told_about_each_other() {
+    let (server, address) = start_server().await;
+    let mut alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+
+    let connect = alice.expect_from(PacketType::Connect, bob.id).await.unwrap();
+    assert_eq!(connect.decode::<ConnectPacket>().packet.client_name, "bob");
+    let connect = bob.expect_from(PacketType::Connect, alice.id).await.unwrap();
+    assert_eq!(connect.decode::<ConnectPacket>().packet.client_name, "alice");
+
+    let players = server.read().await.players.clone();
+    assert_eq!(players.connected().len(), 2);
+}
+
+#[tokio::test]
+async fn player_packets_are_relayed_to_everyone_else() {
+    let (_server, address) = start_server().await;
+    let mut alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    let mut carol = Bot::connect(address, "carol").await.unwrap();
+
+    alice.send_position([1.0, 2.0, 3.0]).await.unwrap();
+
+    for receiver in [&mut bob, &mut carol] {
+        let player = receiver.expect_from(PacketType::Player, alice.id).await.unwrap();
+        let position = player.decode::<PlayerPacket>().packet.position;
+        assert_eq!((position.x, position.y, position.z), (1.0, 2.0, 3.0));
+    }
+    alice.expect_none(PacketType::Player, QUIET).await.unwrap();
+}
+
+#[tokio::test]
+async fn disconnected_players_stop_receiving() {
+    let (server, address) = start_server().await;
+    let mut events = server.read().await.events.subscribe();
+    let alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    let alice_id = alice.id;
+
+    // Stops sending but keeps reading
+    let (sender, mut receiver) = alice.into_split();
+    sender.close().await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, .. } if *id == alice_id)).await;
+
+    let players = server.read().await.players.clone();
+    assert!(!players.get(&alice_id).unwrap().is_connected());
+    assert_eq!(players.connected().len(), 1);
+    // The server closes its side too, which stops the writer task
+    time::timeout(Duration::from_secs(2), async {
+        while receiver.recv().await.is_ok() {}
+    }).await.expect("the socket is still open");
+    time::timeout(Duration::from_secs(2), players.get(&alice_id).unwrap().outgoing.closed()).await
+        .expect("the writer task is still running");
+
+    // Relaying to nobody still works
+    bob.send_position([0.0, 0.0, 0.0]).await.unwrap();
+    bob.expect_none(PacketType::Player, QUIET).await.unwrap();
+}
+
+#[tokio::test]
+async fn reconnecting_keeps_the_slot() {
+    let (server, address) = start_server().await;
+    let mut events = server.read().await.events.subscribe();
+    let alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    let alice_id = alice.id;
+
+    let slot = match next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == alice_id)).await {
+        ServerEvent::PlayerJoined { slot, .. } => slot,
+        _ => unreachable!(),
+    };
+
+    alice.close().await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, .. } if *id == alice_id)).await;
+
+    let mut alice = Bot::reconnect(address, alice_id, "alice").await.unwrap();
+    match next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == alice_id)).await {
+        ServerEvent::PlayerJoined { slot: rejoined_slot, reconnect, .. } => {
+            assert!(reconnect);
+            assert_eq!(rejoined_slot, slot);
+        },
+        _ => unreachable!(),
+    }
+
+    bob.send_position([4.0, 5.0, 6.0]).await.unwrap();
+    let player = alice.expect_from(PacketType::Player, bob.id).await.unwrap();
+    assert_eq!(player.decode::<PlayerPacket>().packet.position.x, 4.0);
+}
+
+#[tokio::test]
+async fn replacing_a_connection_with_the_same_uuid() {
+    let (server, address) = start_server().await;
+    let mut first = Bot::connect(address, "alice").await.unwrap();
+    let mut second = Bot::reconnect(address, first.id, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    second.expect_from(PacketType::Connect, bob.id).await.unwrap();
+
+    let players = server.read().await.players.clone();
+    assert_eq!(players.connected().len(), 2);
+
+    // The replaced connection is closed
+    time::timeout(Duration::from_secs(2), async {
+        while first.recv().await.is_ok() {}
+    }).await.expect("the replaced connection is still open");
+
+    bob.send_position([7.0, 8.0, 9.0]).await.unwrap();
+    second.expect_from(PacketType::Player, bob.id).await.unwrap();
+}
+
+#[tokio::test]
+async fn collected_shines_are_synced() {
+    let (_server, address) = start_server().await;
+    let mut alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    alice.send_costume("Mario", "Mario").await.unwrap();
+    bob.send_costume("MarioTuxedo", "MarioTuxedo").await.unwrap();
+
+    alice.send_shine(42).await.unwrap();
+
+    let shine = bob.expect(PacketType::Shine).await.unwrap();
+    assert_eq!(shine.decode::<ShinePacket>().packet.shine_id, 42);
+    // Shines are sent as if the receiver collected them
+    assert_eq!(shine.sender(), bob.id);
+    alice.expect_none(PacketType::Shine, QUIET).await.unwrap();
+
+    // Players who join later get them once their save has loaded
+    let mut carol = Bot::connect(address, "carol").await.unwrap();
+    carol.expect_none(PacketType::Shine, QUIET).await.unwrap();
+    carol.send_costume("Mario", "Mario").await.unwrap();
+    let shine = carol.expect(PacketType::Shine).await.unwrap();
+    assert_eq!(shine.decode::<ShinePacket>().packet.shine_id, 42);
+}
+
+#[tokio::test]
+async fn shines_are_ignored_before_the_save_loads() {
+    let (server, address) = start_server().await;
+    let mut alice = Bot::connect(address, "alice").await.unwrap();
+    let _bob = Bot::connect(address, "bob").await.unwrap();
+
+    // Loading a save replays every shine it has
+    alice.send_shine(7).await.unwrap();
+    time::sleep(QUIET).await;
+
+    assert!(server.read().await.shines.all().is_empty());
+}
+
+#[tokio::test]
+async fn packets_are_split_from_the_stream() {
+    let (_server, address) = start_server().await;
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+
+    let id = Uuid::new_v4();
+    let mut connect = IPacket::<ConnectPacket>::new();
+    connect.packet.client_name = "alice".to_string();
+    let mut stream = frame(id, PacketType::Connect, &connect);
+    for x in [1.0, 2.0, 3.0] {
+        stream.extend(frame(id, PacketType::Player, &bot::player_packet([x, 0.0, 0.0])));
+    }
+
+    // Several packets in one write, then one packet over two writes
+    let mut alice = TcpStream::connect(address).await.unwrap();
+    let (first, rest) = stream.split_at(stream.len() - 30);
+    alice.write_all(first).await.unwrap();
+    time::sleep(QUIET).await;
+    alice.write_all(rest).await.unwrap();
+
+    for x in [1.0, 2.0, 3.0] {
+        let player = bob.expect_from(PacketType::Player, id).await.unwrap();
+        assert_eq!(player.decode::<PlayerPacket>().packet.position.x, x);
+    }
+}
+
+// Drops every CapPacket, so a test can tell a hook added to the builder ran
+struct DropCaps;
+
+impl PacketHook for DropCaps {
+    fn name(&self) -> &str {
+        "drop caps"
+    }
+
+    fn on_receive(&self, _settings: &Settings, _sender: &PlayerView, packet: &mut HookPacket) -> HookAction {
+        if packet.packet_type() == PacketType::Cap {
+            HookAction::Drop
+        } else {
+            HookAction::Continue
+        }
+    }
+}
+
+// Sends every receiver each PlayerPacket twice
+struct Doubler;
+
+impl PacketHook for Doubler {
+    fn name(&self) -> &str {
+        "doubler"
+    }
+
+    fn on_relay(&self, _settings: &Settings, _sender: &PlayerView, _receiver: &PlayerView, packet: &mut HookPacket) -> HookAction {
+        if packet.packet_type() == PacketType::Player {
+            HookAction::Replace(vec![packet.copy(), packet.copy()])
+        } else {
+            HookAction::Continue
+        }
+    }
+}
+
+#[tokio::test]
+async fn hooks_can_fan_packets_out() {
+    let server = ServerBuilder::new().bind("127.0.0.1:0").hook(Doubler).start().await.unwrap();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    alice.expect_from(PacketType::Connect, bob.id).await.unwrap();
+
+    alice.send_position([1.0, 2.0, 3.0]).await.unwrap();
+    for _ in 0..2 {
+        let player = bob.expect_from(PacketType::Player, alice.id).await.unwrap();
+        assert_eq!(player.decode::<PlayerPacket>().packet.position.x, 1.0);
+    }
+    bob.expect_none(PacketType::Player, QUIET).await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limit_only_drops_streamed_packets() {
+    let mut settings = Settings::defaults();
+    settings.rate_limit.enabled = true;
+    settings.rate_limit.packets_per_second = 1;
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    alice.expect_from(PacketType::Connect, bob.id).await.unwrap();
+
+    for x in [1.0, 2.0, 3.0] {
+        alice.send_position([x, 0.0, 0.0]).await.unwrap();
+    }
+    bob.expect_from(PacketType::Player, alice.id).await.unwrap();
+    bob.expect_none(PacketType::Player, QUIET).await.unwrap();
+
+    alice.send_stage("CapWorldHomeStage", 1).await.unwrap();
+    alice.send_stage("WaterfallWorldHomeStage", 1).await.unwrap();
+    bob.expect_from(PacketType::Game, alice.id).await.unwrap();
+    bob.expect_from(PacketType::Game, alice.id).await.unwrap();
+}
+
+#[tokio::test]
+async fn builder_adds_hooks_and_subscribers() {
+    let (joined, mut joins) = mpsc::unbounded_channel();
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .hook(DropCaps)
+        .on_event(move |event| {
+            if let ServerEvent::PlayerJoined { name, .. } = event {
+                let _ = joined.send(name);
+            }
+        })
+        .start()
+        .await
+        .unwrap();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+
+    let mut names = Vec::new();
+    for _ in 0..2 {
+        names.push(time::timeout(Duration::from_secs(2), joins.recv()).await.unwrap().unwrap());
+    }
+    names.sort();
+    assert_eq!(names, ["alice", "bob"]);
+
+    let mut players: Vec<String> = server.players().await.into_iter().map(|player| player.name).collect();
+    players.sort();
+    assert_eq!(players, ["alice", "bob"]);
+
+    alice.send(&bot::cap_packet([0.0, 0.0, 0.0], true)).await.unwrap();
+    bob.expect_none(PacketType::Cap, QUIET).await.unwrap();
+    alice.send_position([1.0, 1.0, 1.0]).await.unwrap();
+    bob.expect_from(PacketType::Player, alice.id).await.unwrap();
+}
+
+#[tokio::test]
+async fn handle_runs_commands_and_shuts_down() {
+    let server = ServerBuilder::new().bind("127.0.0.1:0").start().await.unwrap();
+    let address = server.local_addr();
+    let mut events = server.server().read().await.events.subscribe();
+    let alice = Bot::connect(address, "alice").await.unwrap();
+    let mut bob = Bot::connect(address, "bob").await.unwrap();
+    let alice_id = alice.id;
+    bob.expect_from(PacketType::Connect, alice_id).await.unwrap();
+
+    assert_eq!(server.kick("alice").await.unwrap(), "Kicked alice");
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, .. } if *id == alice_id)).await;
+    assert!(server.kick("alice").await.is_err());
+    assert!(server.execute("list").await.unwrap().starts_with("bob"));
+    assert!(server.warp("bob", "nowhere", None).await.is_err());
+
+    server.shutdown().await.unwrap();
+    match next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { .. })).await {
+        ServerEvent::PlayerLeft { id, reason, .. } => {
+            assert_eq!(id, bob.id);
+            assert_eq!(reason, DisconnectReason::Shutdown);
+        },
+        _ => unreachable!(),
+    }
+
+    // Bob's socket is closed once whatever was queued for him is sent
+    let closed = time::timeout(Duration::from_secs(2), async {
+        loop {
+            if let Err(error) = bob.recv().await {
+                return error;
+            }
+        }
+    }).await.unwrap();
+    assert_eq!(closed.kind(), ErrorKind::UnexpectedEof);
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_tells_players_and_saves_state() {
+    let directory = env::temp_dir().join(format!("smo-shutdown-{}", Uuid::new_v4()));
+    fs::create_dir(&directory).unwrap();
+    let settings = || {
+        let mut settings = Settings::defaults();
+        for (persist, file_name) in [
+            (&mut settings.persist_shines, "moons.json"),
+            (&mut settings.persist_bans, "bans.json"),
+            (&mut settings.persist_stats, "stats.prom"),
+        ] {
+            persist.enabled = true;
+            persist.file_name = directory.join(file_name).to_str().unwrap().to_string();
+        }
+        settings
+    };
+
+    let mut first = settings();
+    first.banned_players.players.push(Uuid::nil());
+    let server = ServerBuilder::new().settings(first).bind("127.0.0.1:0").start().await.unwrap();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    alice.send_costume("Mario", "Mario").await.unwrap();
+    bob.send_costume("Mario", "Mario").await.unwrap();
+    alice.send_shine(42).await.unwrap();
+    bob.expect(PacketType::Shine).await.unwrap();
+
+    server.shutdown().await.unwrap();
+    bob.expect_from(PacketType::Disconnect, alice.id).await.unwrap();
+    alice.expect_from(PacketType::Disconnect, bob.id).await.unwrap();
+    assert!(fs::read_to_string(directory.join("stats.prom")).unwrap().contains("smo_disconnects_total{reason=\"shutdown\"}"));
+
+    let server = ServerBuilder::new().settings(settings()).bind("127.0.0.1:0").start().await.unwrap();
+    {
+        let state = server.server();
+        let state = state.read().await;
+        assert_eq!(state.shines.all(), [42]);
+        assert_eq!(state.settings.banned_players.players, [Uuid::nil()]);
+    }
+    server.shutdown().await.unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn settings_file_changes_are_applied() {
+    let path = env::temp_dir().join(format!("smo-settings-{}.json", Uuid::new_v4()));
+    let server = ServerBuilder::new().settings_file(&path).bind("127.0.0.1:0").start().await.unwrap();
+    let mut events = server.server().read().await.events.subscribe();
+    let alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    let alice_id = alice.id;
+    let _bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    assert_eq!(server.reload_settings().await.unwrap(), "Settings are unchanged");
+
+    let mut settings: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    settings["banned_players"]["enabled"] = true.into();
+    settings["banned_players"]["players"] = serde_json::json!([alice_id]);
+    settings["server"]["port"] = 1028.into();
+    fs::write(&path, settings.to_string()).unwrap();
+
+    let message = server.execute("settings reload").await.unwrap();
+    assert!(message.contains("changed banned_players.enabled, banned_players.players, server.port"), "{}", message);
+    assert!(message.contains("Restart the server to apply server.port"), "{}", message);
+    assert!(message.contains("Kicked alice"), "{}", message);
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, reason: DisconnectReason::Kicked, .. } if *id == alice_id)).await;
+    assert!(server.server().read().await.settings.banned_players.bans_player(&alice_id));
+
+    // A file that doesn't validate leaves the live settings alone
+    settings["banned_players"]["ip_addresses"] = serde_json::json!(["not an address"]);
+    fs::write(&path, settings.to_string()).unwrap();
+    assert!(server.reload_settings().await.unwrap_err().contains("not an address"));
+    assert!(server.server().read().await.settings.banned_players.ip_addresses.is_empty());
+
+    server.shutdown().await.unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bans_survive_a_reload() {
+    let path = env::temp_dir().join(format!("smo-settings-{}.json", Uuid::new_v4()));
+    let mut settings = Settings::defaults();
+    settings.banned_players.enabled = true;
+    settings.banned_players.players = vec![Uuid::from_u128(1), Uuid::from_u128(2)];
+    let server = ServerBuilder::new().settings(settings).settings_file(&path).bind("127.0.0.1:0").start().await.unwrap();
+    {
+        let state = server.server();
+        let banned_players = &mut state.write().await.settings.banned_players;
+        banned_players.players.retain(|id| *id != Uuid::from_u128(2));
+        banned_players.players.push(Uuid::from_u128(3));
+        banned_players.ip_addresses.push("10.0.0.1".to_string());
+    }
+    assert_eq!(server.reload_settings().await.unwrap(), "Settings are unchanged");
+
+    // Changes to the file still apply alongside the ones made while running, 2 was in the file before so stays lifted
+    let mut file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    file["banned_players"]["players"] = serde_json::json!([Uuid::from_u128(2), Uuid::from_u128(4)]);
+    fs::write(&path, file.to_string()).unwrap();
+    server.reload_settings().await.unwrap();
+    let banned_players = server.server().read().await.settings.banned_players.clone();
+    assert_eq!(banned_players.players, [Uuid::from_u128(4), Uuid::from_u128(3)]);
+    assert_eq!(banned_players.ip_addresses, ["10.0.0.1"]);
+
+    server.shutdown().await.unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn overrides_win_over_the_settings_file() {
+    let path = env::temp_dir().join(format!("smo-settings-{}.json", Uuid::new_v4()));
+    let overrides = SettingsOverrides { max_players: Some(1), ..SettingsOverrides::default() };
+    let server = ServerBuilder::new().settings_file(&path).overrides(overrides).bind("127.0.0.1:0").start().await.unwrap();
+    let mut events = server.server().read().await.events.subscribe();
+    let alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    assert_eq!(alice.max_players, 1);
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == alice.id)).await;
+
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    assert!(matches!(bob.recv().await, Err(error) if error.kind() == ErrorKind::UnexpectedEof));
+
+    // Still applied after the file is reloaded
+    let mut settings: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    settings["server"]["max_players"] = 8.into();
+    fs::write(&path, settings.to_string()).unwrap();
+    assert_eq!(server.reload_settings().await.unwrap(), "Settings are unchanged");
+    assert_eq!(server.server().read().await.settings.server.max_players(), 1);
+
+    server.shutdown().await.unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bad_addresses_are_refused() {
+    // Even when the settings file is missing and gets created
+    let path = env::temp_dir().join(format!("smo-settings-{}.json", Uuid::new_v4()));
+    let overrides = SettingsOverrides { address: Some("0.0.0.O".to_string()), ..SettingsOverrides::default() };
+    let error = Settings::load(&path, &overrides).err().unwrap();
+    assert!(error.to_string().contains("0.0.0.O"), "{}", error);
+    fs::remove_file(&path).unwrap();
+
+    let mut settings = Settings::defaults();
+    settings.server.address = "localhost".to_string();
+    assert!(settings.server.bind_address().is_err());
+    let error = ServerBuilder::new().settings(settings).start().await.err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn only_allowed_players_get_in() {
+    let mut settings = Settings::defaults();
+    settings.allowed_players.enabled = true;
+    settings.allowed_players.names = vec!["Alice".to_string()];
+    settings.allowed_players.password = Some("hunter2".to_string());
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+    let mut events = server.server().read().await.events.subscribe();
+
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == alice.id)).await;
+
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    assert!(matches!(bob.recv().await, Err(error) if error.kind() == ErrorKind::UnexpectedEof));
+    let mut bob = Bot::connect_with_password(server.local_addr(), "bob", "wrong").await.unwrap();
+    assert!(matches!(bob.recv().await, Err(error) if error.kind() == ErrorKind::UnexpectedEof));
+
+    // The password isn't passed on to anyone else
+    let carol = Bot::connect_with_password(server.local_addr(), "carol", "hunter2").await.unwrap();
+    let connect = alice.expect_from(PacketType::Connect, carol.id).await.unwrap();
+    assert_eq!(connect.decode::<ConnectPacket>().packet.client_name, "carol");
+    assert_eq!(connect.decode::<ConnectPacket>().packet.password, "");
+
+    assert_eq!(server.execute("allow bob").await.unwrap(), "bob can join until the server restarts");
+    let bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == bob.id)).await;
+
+    server.execute("disallow bob").await.unwrap();
+    assert!(server.execute("disallow bob").await.is_err());
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn players_left_out_of_a_smaller_allowlist_are_kicked() {
+    let path = env::temp_dir().join(format!("smo-settings-{}.json", Uuid::new_v4()));
+    let mut settings = Settings::defaults();
+    settings.allowed_players.enabled = true;
+    settings.allowed_players.names = vec!["alice".to_string(), "bob".to_string()];
+    let server = ServerBuilder::new().settings(settings).settings_file(&path).bind("127.0.0.1:0").start().await.unwrap();
+    let mut events = server.server().read().await.events.subscribe();
+    let alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == alice.id)).await;
+    let bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == bob.id)).await;
+
+    let mut file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    file["allowed_players"]["names"] = serde_json::json!(["alice"]);
+    fs::write(&path, file.to_string()).unwrap();
+    let message = server.reload_settings().await.unwrap();
+    assert!(message.ends_with("Kicked bob, who can't join anymore"), "{}", message);
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, reason: DisconnectReason::Kicked, .. } if *id == bob.id)).await;
+    assert!(server.server().read().await.players.get(&alice.id).unwrap().is_connected());
+
+    server.shutdown().await.unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn packets_before_connecting_are_refused() {
+    let mut settings = Settings::defaults();
+    settings.allowed_players.enabled = true;
+    settings.allowed_players.names = vec!["alice".to_string()];
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+
+    // Everything the game would send once connected, but no ConnectPacket
+    let id = Uuid::new_v4();
+    let mut stream = frame(id, PacketType::Player, &bot::player_packet([1.0, 2.0, 3.0]));
+    stream.extend(frame(id, PacketType::Costume, &IPacket::<CostumePacket>::new()));
+    stream.extend(frame(id, PacketType::Shine, &bot::shine_packet(42)));
+    let mut mallory = TcpStream::connect(server.local_addr()).await.unwrap();
+    mallory.write_all(&stream).await.unwrap();
+    wait_for_close(mallory).await;
+
+    alice.expect_none(PacketType::Player, QUIET).await.unwrap();
+    assert!(server.server().read().await.shines.all().is_empty());
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn banned_players_are_never_relayed() {
+    let banned = Uuid::new_v4();
+    let mut settings = Settings::defaults();
+    settings.banned_players.enabled = true;
+    settings.banned_players.players = vec![banned];
+    let server = ServerBuilder::new().settings(settings).bind("127.0.0.1:0").start().await.unwrap();
+    let mut events = server.server().read().await.events.subscribe();
+    let mut alice = Bot::connect(server.local_addr(), "alice").await.unwrap();
+
+    // Without a ConnectPacket
+    let mut mallory = TcpStream::connect(server.local_addr()).await.unwrap();
+    mallory.write_all(&frame(banned, PacketType::Player, &bot::player_packet([1.0, 2.0, 3.0]))).await.unwrap();
+    wait_for_close(mallory).await;
+    alice.expect_none(PacketType::Player, QUIET).await.unwrap();
+
+    // Banned after joining without being kicked, the next packet is dropped and the player kicked
+    let mut bob = Bot::connect(server.local_addr(), "bob").await.unwrap();
+    let bob_id = bob.id;
+    alice.expect_from(PacketType::Connect, bob_id).await.unwrap();
+    server.server().write().await.settings.banned_players.players.push(bob_id);
+    bob.send_position([4.0, 5.0, 6.0]).await.unwrap();
+    next_event(&mut events, |event| matches!(event, ServerEvent::PlayerLeft { id, reason: DisconnectReason::Kicked, .. } if *id == bob_id)).await;
+    alice.expect_none(PacketType::Player, QUIET).await.unwrap();
+    server.shutdown().await.unwrap();
+}